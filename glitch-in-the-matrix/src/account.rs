@@ -0,0 +1,123 @@
+//! Account management: passwords, deactivation, and third-party identifiers.
+//!
+//! Endpoints the spec protects with User-Interactive Authentication return a
+//! `UiaaRequest`, which the caller drives to completion with whichever client
+//! they like (see the `uiaa` module).
+
+use types::replies::{RequestTokenReply, ThirdPartyIdsReply, UnbindReply};
+use crate::request::{MatrixRequest, MatrixRequestable};
+use crate::uiaa::UiaaRequest;
+use http::Method;
+use serde_json::json;
+use std::future::Future;
+use crate::errors::MatrixResult;
+
+/// Contains methods relating to `/account/` endpoints.
+pub struct Account;
+
+impl Account {
+    /// Change the user's password (UIAA-protected, and usually needs the old
+    /// password).
+    ///
+    /// If `logout_devices` is true, all of the user's other devices are logged
+    /// out too.
+    pub fn change_password(new_password: &str, logout_devices: bool) -> UiaaRequest<'static> {
+        UiaaRequest::new(MatrixRequest::new_with_body_ser(Method::POST, "/account/password", json!({
+            "new_password": new_password,
+            "logout_devices": logout_devices
+        })))
+    }
+    /// Deactivate the user's account, so that it can't be logged into or used
+    /// again (UIAA-protected). The reply is an `UnbindReply`.
+    ///
+    /// - `id_server`: the identity server to unbind third-party identifiers
+    ///   from, if not the one they were bound with.
+    /// - `erase`: whether to ask the homeserver to forget the user's messages
+    ///   as well.
+    pub fn deactivate(id_server: Option<&str>, erase: bool) -> UiaaRequest<'static> {
+        let mut body = json!({ "erase": erase });
+        if let Some(is) = id_server {
+            body["id_server"] = json!(is);
+        }
+        UiaaRequest::new(MatrixRequest::new_with_body_ser(Method::POST, "/account/deactivate", body))
+    }
+    /// Get the third-party identifiers bound to the user's account.
+    pub fn get_3pids<R: MatrixRequestable>(rq: &R) -> impl Future<Output = MatrixResult<ThirdPartyIdsReply>> {
+        MatrixRequest::new_basic(Method::GET, "/account/3pid")
+            .send(rq)
+    }
+    /// Ask the homeserver to email a validation link to `email`, so that it
+    /// can be added to the user's account with `add_3pid`.
+    ///
+    /// `client_secret` is a secret you make up, which must be passed to
+    /// `add_3pid` along with the returned session ID; `send_attempt` should be
+    /// incremented to send the email again.
+    pub fn request_email_token<R: MatrixRequestable>(rq: &R, email: &str, client_secret: &str, send_attempt: u32) -> impl Future<Output = MatrixResult<RequestTokenReply>> {
+        MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/email/requestToken", json!({
+            "email": email,
+            "client_secret": client_secret,
+            "send_attempt": send_attempt
+        })).send(rq)
+    }
+    /// Ask the homeserver to text a validation token to a phone number, so that
+    /// it can be added to the user's account with `add_3pid`.
+    ///
+    /// `country` is the two-letter country code the number is from; otherwise
+    /// this works like `request_email_token`.
+    pub fn request_msisdn_token<R: MatrixRequestable>(rq: &R, country: &str, phone_number: &str, client_secret: &str, send_attempt: u32) -> impl Future<Output = MatrixResult<RequestTokenReply>> {
+        MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/msisdn/requestToken", json!({
+            "country": country,
+            "phone_number": phone_number,
+            "client_secret": client_secret,
+            "send_attempt": send_attempt
+        })).send(rq)
+    }
+    /// Add a validated third-party identifier to the user's account
+    /// (UIAA-protected).
+    pub fn add_3pid(client_secret: &str, sid: &str) -> UiaaRequest<'static> {
+        UiaaRequest::new(MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/add", json!({
+            "client_secret": client_secret,
+            "sid": sid
+        })))
+    }
+    /// Bind a validated third-party identifier to the user's account on an
+    /// identity server, so other users can find them with it.
+    pub fn bind_3pid<R: MatrixRequestable>(rq: &R, client_secret: &str, sid: &str, id_server: &str, id_access_token: &str) -> impl Future<Output = MatrixResult<()>> {
+        MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/bind", json!({
+            "client_secret": client_secret,
+            "sid": sid,
+            "id_server": id_server,
+            "id_access_token": id_access_token
+        })).discarding_send(rq)
+    }
+    /// Unbind a third-party identifier from an identity server, without
+    /// removing it from the user's account.
+    ///
+    /// If `id_server` is `None`, the homeserver uses the one it was bound with.
+    pub fn unbind_3pid<R: MatrixRequestable>(rq: &R, medium: &str, address: &str, id_server: Option<&str>) -> impl Future<Output = MatrixResult<UnbindReply>> {
+        let mut body = json!({
+            "medium": medium,
+            "address": address
+        });
+        if let Some(is) = id_server {
+            body["id_server"] = json!(is);
+        }
+        MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/unbind", body)
+            .send(rq)
+    }
+    /// Remove a third-party identifier from the user's account, unbinding it
+    /// from any identity server as well.
+    ///
+    /// If `id_server` is `None`, the homeserver uses the one it was bound with.
+    pub fn delete_3pid<R: MatrixRequestable>(rq: &R, medium: &str, address: &str, id_server: Option<&str>) -> impl Future<Output = MatrixResult<UnbindReply>> {
+        let mut body = json!({
+            "medium": medium,
+            "address": address
+        });
+        if let Some(is) = id_server {
+            body["id_server"] = json!(is);
+        }
+        MatrixRequest::new_with_body_ser(Method::POST, "/account/3pid/delete", body)
+            .send(rq)
+    }
+}
@@ -0,0 +1,50 @@
+//! Device management.
+//!
+//! Deleting devices is protected by User-Interactive Authentication, so those
+//! methods return a `UiaaRequest` for the caller to drive (see the `uiaa` module).
+
+use types::replies::{Device, DevicesReply};
+use crate::request::{MatrixRequest, MatrixRequestable};
+use crate::uiaa::UiaaRequest;
+use http::Method;
+use serde_json::json;
+use std::future::Future;
+use crate::errors::MatrixResult;
+
+/// Contains methods relating to `/devices/` endpoints.
+pub struct Devices;
+
+impl Devices {
+    /// Get all of the user's devices.
+    pub fn get_devices<R: MatrixRequestable>(rq: &R) -> impl Future<Output = MatrixResult<Vec<Device>>> {
+        let fut = MatrixRequest::new_basic(Method::GET, "/devices")
+            .send(rq);
+        async move {
+            let rpl: DevicesReply = fut.await?;
+            Ok(rpl.devices)
+        }
+    }
+    /// Get one of the user's devices.
+    pub fn get_device<R: MatrixRequestable>(rq: &R, device_id: &str) -> impl Future<Output = MatrixResult<Device>> {
+        MatrixRequest::new_basic(Method::GET, format!("/devices/{}", device_id))
+            .send(rq)
+    }
+    /// Set the display name of one of the user's devices.
+    pub fn set_display_name<R: MatrixRequestable>(rq: &R, device_id: &str, name: &str) -> impl Future<Output = MatrixResult<()>> {
+        MatrixRequest::new_with_body_ser(Method::PUT, format!("/devices/{}", device_id), json!({
+            "display_name": name
+        })).discarding_send(rq)
+    }
+    /// Delete one of the user's devices, invalidating its access token
+    /// (UIAA-protected).
+    pub fn delete_device(device_id: &str) -> UiaaRequest<'static> {
+        UiaaRequest::new(MatrixRequest::new_with_body_ser(Method::DELETE, format!("/devices/{}", device_id), json!({})))
+    }
+    /// Delete several of the user's devices at once (UIAA-protected).
+    pub fn delete_devices<S: AsRef<str>>(device_ids: &[S]) -> UiaaRequest<'static> {
+        let ids = device_ids.iter().map(|x| x.as_ref()).collect::<Vec<_>>();
+        UiaaRequest::new(MatrixRequest::new_with_body_ser(Method::POST, "/delete_devices", json!({
+            "devices": ids
+        })))
+    }
+}
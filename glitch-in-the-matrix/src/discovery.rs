@@ -0,0 +1,119 @@
+//! Finding a homeserver from a user ID or server name.
+
+use crate::errors::*;
+use crate::request::{MatrixRequest, MatrixRequestable};
+use crate::request::apis::{UnversionedClientApi, WellKnownApi};
+use crate::types::replies::{VersionsReply, WellKnownReply};
+use crate::{MatrixClient, MatrixClientBuilder, PendingClient};
+use http::{Method, Uri};
+use std::sync::Arc;
+
+/// A homeserver found with `DiscoveredServer::discover`.
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    /// The base URL of the homeserver, without a trailing slash.
+    pub homeserver_url: String,
+    /// The base URL of the identity server the user should use, if any.
+    pub identity_server_url: Option<String>,
+    /// The spec versions and unstable features the homeserver supports.
+    pub versions: VersionsReply
+}
+impl DiscoveredServer {
+    /// Find the homeserver for a user ID (e.g. `@user:example.org`) or server
+    /// name (e.g. `example.org`), using the default client options.
+    ///
+    /// See `MatrixClientBuilder::discover`.
+    pub async fn discover(name: &str) -> MatrixResult<Self> {
+        MatrixClientBuilder::new().discover(name).await
+    }
+    /// Find the homeserver for a user ID or server name, like
+    /// `MatrixClientBuilder::discover`, but make the requests with whatever
+    /// `make_client` returns when given a base URL (e.g. `https://example.org`).
+    ///
+    /// This is mostly useful for testing.
+    pub async fn discover_using<R, F>(name: &str, mut make_client: F) -> MatrixResult<Self>
+        where R: MatrixRequestable, F: FnMut(String) -> MatrixResult<R> {
+        let (homeserver_url, identity_server_url) = if name.contains("://") {
+            (validate_url(name)?, None)
+        }
+        else {
+            let server = format!("https://{}", server_name(name));
+            let fut = MatrixRequest::new(Method::GET, "/client", (), WellKnownApi)
+                .send::<_, WellKnownReply>(&make_client(server.clone())?);
+            match fut.await {
+                Ok(wk) => {
+                    let is = match wk.identity_server {
+                        Some(is) => Some(validate_url(&is.base_url)?),
+                        None => None
+                    };
+                    (validate_url(&wk.homeserver.base_url)?, is)
+                },
+                Err(ref e) if e.is_not_found() => (server, None),
+                Err(e) => {
+                    return Err(MatrixError::DiscoveryFailed(format!("couldn't get .well-known: {}", e)));
+                }
+            }
+        };
+        let versions = MatrixRequest::new(Method::GET, "/versions", (), UnversionedClientApi)
+            .send(&make_client(homeserver_url.clone())?)
+            .await
+            .map_err(|e| MatrixError::DiscoveryFailed(format!("couldn't get versions from {}: {}", homeserver_url, e)))?;
+        Ok(DiscoveredServer {
+            homeserver_url,
+            identity_server_url,
+            versions
+        })
+    }
+    /// Make a `MatrixClientBuilder` that already knows what this server supports,
+    /// so it doesn't need to ask again.
+    ///
+    /// You still need to pass `homeserver_url` to whichever constructor you call.
+    pub fn builder(&self) -> MatrixClientBuilder {
+        MatrixClientBuilder::new().server_versions(self.versions.clone())
+    }
+}
+/// Gets the server name out of a user ID, or returns `name` unchanged if it isn't one.
+fn server_name(name: &str) -> &str {
+    match name.find(':') {
+        Some(idx) if name.starts_with('@') => &name[idx+1..],
+        _ => name
+    }
+}
+/// Checks that `url` is a usable base URL, and strips any trailing slashes from it.
+fn validate_url(url: &str) -> MatrixResult<String> {
+    let url = url.trim_end_matches('/');
+    let uri: Uri = url.parse()
+        .map_err(|_| MatrixError::DiscoveryFailed(format!("invalid base URL: {}", url)))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => Ok(url.to_string()),
+        _ => Err(MatrixError::DiscoveryFailed(format!("invalid base URL: {}", url)))
+    }
+}
+impl MatrixClientBuilder {
+    /// Find the homeserver for a user ID (e.g. `@user:example.org`) or server
+    /// name (e.g. `example.org`).
+    ///
+    /// This follows the client-server spec's discovery process: it fetches
+    /// `https://<server name>/.well-known/matrix/client` (falling back to the
+    /// server name itself if there isn't one), checks the URLs in it are valid,
+    /// and then asks the homeserver which versions of the spec it supports.
+    ///
+    /// `name` can also be the URL of a homeserver, in which case the
+    /// `.well-known` lookup is skipped.
+    pub async fn discover(&self, name: &str) -> MatrixResult<DiscoveredServer> {
+        DiscoveredServer::discover_using(name, |url| {
+            Ok(MatrixClient {
+                inner: Arc::new(self.make_inner(String::new(), url, false)?),
+                user_id: String::new()
+            })
+        }).await
+    }
+}
+impl crate::MatrixClientInner {
+    /// Asks the homeserver which versions of the spec it supports.
+    pub(crate) async fn fetch_versions(&self) -> MatrixResult<VersionsReply> {
+        MatrixRequest::new(Method::GET, "/versions", (), UnversionedClientApi)
+            .send(&PendingClient { inner: self })
+            .await
+    }
+}
@@ -0,0 +1,181 @@
+//! Dispatching events from `/sync` to handlers, so you don't have to write the
+//! same big `match` in every bot.
+//!
+//! Register handlers on a `Dispatcher`, each with something to match events
+//! against, and then feed it sync replies with `run` (or `dispatch`). Every
+//! matching handler is run in its own task, so they run concurrently, and one
+//! failing (or panicking) doesn't affect the others.
+
+use types::content::Content;
+use types::content::room::types::Membership;
+use types::events::Event;
+use types::sync::{EventSource, RoomCategory, SyncEvent, SyncReply};
+use crate::errors::*;
+use crate::request::MatrixRequestable;
+use crate::room::{Room, RoomClient, RoomExt};
+use futures::{Stream, StreamExt};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::any::Any;
+use std::future::Future;
+use std::sync::Arc;
+
+/// Information about an event passed to its handler, along with the event itself.
+#[derive(Clone, Debug)]
+pub struct EventContext<R> {
+    /// The client the `Dispatcher` was made with.
+    pub client: R,
+    /// The room the event is in, if it's in one.
+    pub room: Option<Room<'static>>,
+    /// Where in the sync reply the event came from.
+    pub source: EventSource,
+    /// The user's membership of the room, if the event is in one.
+    pub category: Option<RoomCategory>
+}
+impl<R> EventContext<R> where R: MatrixRequestable {
+    /// Make a `RoomClient` for the room the event is in, if it's in one.
+    pub fn room_cli(&self) -> Option<RoomClient<'_, 'static, '_, R>> {
+        self.room.as_ref().map(|r| r.cli(&self.client))
+    }
+}
+/// An error from an event handler, as passed to `Dispatcher::on_error`.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The handler returned an error.
+    Failed(MatrixError),
+    /// The handler panicked, with this message (or `Box<dyn Any>`, if the
+    /// panic's payload wasn't a string).
+    Panicked(String),
+    /// The handler's task was cancelled (e.g. because the runtime was shut
+    /// down) before it finished.
+    Cancelled
+}
+/// Get the message out of a panic's payload, which is a `&str` or a `String` if
+/// it came from `panic!` (or `unwrap`, etc.).
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => "Box<dyn Any>".into()
+        }
+    }
+}
+type Matcher = Box<dyn Fn(&SyncEvent<'_>) -> bool + Send + Sync>;
+type Handler<R> = Box<dyn Fn(EventContext<R>, Event) -> BoxFuture<'static, MatrixResult<()>> + Send + Sync>;
+/// Runs handlers for the events in sync replies.
+///
+/// Handlers are async functions (or closures) taking an `EventContext` and an
+/// `Event`; see `on` for details.
+pub struct Dispatcher<R> {
+    client: R,
+    handlers: Vec<(Matcher, Handler<R>)>,
+    on_error: Arc<dyn Fn(HandlerError) + Send + Sync>
+}
+impl<R> Dispatcher<R> where R: MatrixRequestable + Clone + Send + Sync + 'static {
+    /// Make a new `Dispatcher` with no handlers, which passes `client` to the
+    /// handlers it runs.
+    pub fn new(client: R) -> Self {
+        Self {
+            client,
+            handlers: vec![],
+            on_error: Arc::new(|_| {})
+        }
+    }
+    /// Register a handler, to be run for every event that `matcher` returns
+    /// `true` for.
+    ///
+    /// An event can match any number of handlers; they all get run.
+    pub fn on<M, F, Fut>(&mut self, matcher: M, handler: F) -> &mut Self
+        where M: Fn(&SyncEvent<'_>) -> bool + Send + Sync + 'static,
+              F: Fn(EventContext<R>, Event) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = MatrixResult<()>> + Send + 'static {
+        self.handlers.push((Box::new(matcher), Box::new(move |ctx, evt| handler(ctx, evt).boxed())));
+        self
+    }
+    /// Register a handler for every event of a given type (e.g. `m.typing`),
+    /// wherever it comes from.
+    pub fn on_event_type<F, Fut>(&mut self, event_type: &str, handler: F) -> &mut Self
+        where F: Fn(EventContext<R>, Event) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = MatrixResult<()>> + Send + 'static {
+        let event_type = event_type.to_string();
+        self.on(move |evt| evt.event.event_type == event_type, handler)
+    }
+    /// Register a handler for messages (`Content::RoomMessage`) in the
+    /// timelines of rooms the user has joined.
+    ///
+    /// This includes messages the user sent themselves, so check the sender
+    /// if you're going to reply to them.
+    pub fn on_message<F, Fut>(&mut self, handler: F) -> &mut Self
+        where F: Fn(EventContext<R>, Event) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = MatrixResult<()>> + Send + 'static {
+        self.on(|evt| {
+            evt.source == EventSource::Timeline
+                && evt.category == Some(RoomCategory::Joined)
+                && matches!(evt.event.content, Content::RoomMessage(_))
+        }, handler)
+    }
+    /// Register a handler for invites of the client's user to rooms.
+    ///
+    /// The handler gets the invitee's `m.room.member` event.
+    pub fn on_invite<F, Fut>(&mut self, handler: F) -> &mut Self
+        where F: Fn(EventContext<R>, Event) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = MatrixResult<()>> + Send + 'static {
+        let user_id = self.client.get_user_id().into_owned();
+        self.on(move |evt| {
+            evt.source == EventSource::InviteState
+                && evt.event.state_data.as_ref().map(|sd| sd.state_key == user_id).unwrap_or(false)
+                && matches!(evt.event.content, Content::RoomMember(ref m) if matches!(m.membership, Membership::Invite))
+        }, handler)
+    }
+    /// Set a function to be told about handlers that fail or panic.
+    ///
+    /// By default, these errors are ignored.
+    pub fn on_error<F>(&mut self, f: F) -> &mut Self where F: Fn(HandlerError) + Send + Sync + 'static {
+        self.on_error = Arc::new(f);
+        self
+    }
+    /// Start the handlers for all the events in a sync reply.
+    ///
+    /// The handlers are spawned onto the current `tokio` runtime straight away;
+    /// the returned future resolves once they've all finished.
+    pub fn dispatch(&self, sync: &SyncReply) -> impl Future<Output = ()> + Send + 'static {
+        let mut tasks = vec![];
+        for evt in sync.events() {
+            for (matcher, handler) in self.handlers.iter() {
+                if matcher(&evt) {
+                    let ctx = EventContext {
+                        client: self.client.clone(),
+                        room: evt.room.cloned(),
+                        source: evt.source,
+                        category: evt.category
+                    };
+                    tasks.push(tokio::spawn(handler(ctx, evt.event.clone())));
+                }
+            }
+        }
+        let on_error = self.on_error.clone();
+        async move {
+            for res in future::join_all(tasks).await {
+                match res {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => on_error(HandlerError::Failed(e)),
+                    Err(e) if e.is_panic() => on_error(HandlerError::Panicked(panic_message(e.into_panic()))),
+                    Err(_) => on_error(HandlerError::Cancelled)
+                }
+            }
+        }
+    }
+    /// Dispatch the events of every reply from a stream of sync replies (e.g.
+    /// a `SyncStream`), waiting for each reply's handlers to finish before
+    /// moving on to the next.
+    ///
+    /// This only returns if the stream yields an error (which `SyncStream` only
+    /// does for errors it can't recover from by itself), or ends.
+    pub async fn run<S>(&self, mut stream: S) -> MatrixResult<()>
+        where S: Stream<Item = MatrixResult<SyncReply>> + Unpin {
+        while let Some(sync) = stream.next().await {
+            self.dispatch(&sync?).await;
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
 //! Error handling.
+
+// `failure_derive` puts its impls inside a `const` block.
+#![allow(non_local_definitions)]
+
 macro_rules! derive_from {
     ($err:ident, $($var:ident, $ty:ty),*) => {
         $(
@@ -11,21 +15,25 @@ macro_rules! derive_from {
     }
 }
 use failure::Fail;
+use http::StatusCode;
+use types::replies::MatrixErrorCode;
 
 /// Something Matrixy that can go wrong.
 #[derive(Fail, Debug)]
 #[allow(missing_docs)]
 pub enum MatrixError {
     #[fail(display = "HTTP error: {}", _0)]
-    Hyper(#[cause] ::hyper::error::Error),
+    Hyper(#[cause] ::hyper::Error),
+    #[fail(display = "HTTP client error: {}", _0)]
+    HyperClient(#[cause] ::hyper_util::client::legacy::Error),
     #[fail(display = "Serialization error: {}", _0)]
     Serde(#[cause] ::serde_json::Error),
     #[fail(display = "Error decoding URI: {}", _0)]
-    UriError(#[cause] ::hyper::http::uri::InvalidUri),
+    UriError(#[cause] ::http::uri::InvalidUri),
     #[fail(display = "I/O error: {}", _0)]
     Io(#[cause] ::std::io::Error),
     #[fail(display = "OpenSSL error: {}", _0)]
-    Openssl(#[cause] ::hyper_openssl::openssl::error::ErrorStack),
+    Openssl(#[cause] ::openssl::error::ErrorStack),
     /// A request failed with a non-OK HTTP status.
     ///
     /// If the body contained a valid `BadRequestReply`, the `BadRequest` variant will be used
@@ -38,17 +46,83 @@ pub enum MatrixError {
     InvalidHeaderValue(::http::header::InvalidHeaderValue),
     /// A request failed with an error from the homeserver.
     #[fail(display = "Error from homeserver: {:?}", _0)]
-    BadRequest(super::types::replies::BadRequestReply)
+    BadRequest(super::types::replies::BadRequestReply),
+    /// Homeserver discovery failed, for the given reason.
+    #[fail(display = "Homeserver discovery failed: {}", _0)]
+    DiscoveryFailed(String),
+    /// The request needs (more) User-Interactive Authentication; see the
+    /// `uiaa` module.
+    #[fail(display = "Further authentication required: {:?}", _0)]
+    AuthRequired(Box<super::types::replies::UiaaInfo>),
+    /// The homeserver took too long to respond to a request.
+    #[fail(display = "Request timed out")]
+    Timeout,
+    #[cfg(feature = "sqlite")]
+    #[fail(display = "SQLite error: {}", _0)]
+    Sqlite(#[cause] ::rusqlite::Error)
 }
 derive_from!(MatrixError,
-             Hyper, ::hyper::error::Error,
+             Hyper, ::hyper::Error,
+             HyperClient, ::hyper_util::client::legacy::Error,
              Serde, ::serde_json::Error,
-             UriError, ::hyper::http::uri::InvalidUri,
+             UriError, ::http::uri::InvalidUri,
              HttpError, ::http::Error,
              InvalidHeaderValue, ::http::header::InvalidHeaderValue,
              Io, ::std::io::Error,
-             Openssl, ::hyper_openssl::openssl::error::ErrorStack
+             Openssl, ::openssl::error::ErrorStack
             );
+#[cfg(feature = "sqlite")]
+derive_from!(MatrixError, Sqlite, ::rusqlite::Error);
+impl MatrixError {
+    /// If this error came from the homeserver, get its error code.
+    pub fn error_code(&self) -> Option<MatrixErrorCode> {
+        match *self {
+            MatrixError::BadRequest(ref brr) => Some(brr.error_code()),
+            MatrixError::AuthRequired(ref info) => info.error_code(),
+            _ => None
+        }
+    }
+    /// Whether the thing asked for doesn't exist (`M_NOT_FOUND`, or HTTP 404
+    /// without an error code).
+    pub fn is_not_found(&self) -> bool {
+        match *self {
+            MatrixError::HttpCode(sc) => sc == StatusCode::NOT_FOUND,
+            _ => self.error_code() == Some(MatrixErrorCode::NotFound)
+        }
+    }
+    /// Whether the request was rate-limited (`M_LIMIT_EXCEEDED`, or HTTP 429
+    /// without an error code).
+    pub fn is_rate_limited(&self) -> bool {
+        match *self {
+            MatrixError::HttpCode(sc) => sc == StatusCode::TOO_MANY_REQUESTS,
+            _ => self.error_code() == Some(MatrixErrorCode::LimitExceeded)
+        }
+    }
+    /// Whether the request was forbidden (`M_FORBIDDEN`).
+    pub fn is_forbidden(&self) -> bool {
+        self.error_code() == Some(MatrixErrorCode::Forbidden)
+    }
+    /// Whether the access token we used is no good (`M_UNKNOWN_TOKEN` or
+    /// `M_MISSING_TOKEN`), which means we need to log in again.
+    pub fn is_unknown_token(&self) -> bool {
+        matches!(self.error_code(),
+                 Some(MatrixErrorCode::UnknownToken { .. }) | Some(MatrixErrorCode::MissingToken))
+    }
+    /// Whether the homeserver told us to log in again for the same device,
+    /// keeping our local state (`M_UNKNOWN_TOKEN` with `soft_logout` set).
+    pub fn is_soft_logout(&self) -> bool {
+        self.error_code() == Some(MatrixErrorCode::UnknownToken { soft_logout: true })
+    }
+    /// Whether this error is probably temporary, such that making the same
+    /// request again later might work: network errors, timeouts, rate limiting,
+    /// and server errors (HTTP 5xx) without an error code.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            MatrixError::Hyper(_) | MatrixError::HyperClient(_) | MatrixError::Io(_) | MatrixError::Timeout => true,
+            MatrixError::HttpCode(sc) => sc.is_server_error() || sc == StatusCode::TOO_MANY_REQUESTS,
+            _ => self.is_rate_limited()
+        }
+    }
+}
 /// Bog-standard result newtype. You know the drill.
 pub type MatrixResult<T> = Result<T, MatrixError>;
-
@@ -0,0 +1,32 @@
+//! Uploading and fetching filters.
+
+use types::filter::Filter;
+use types::replies::FilterReply;
+use crate::request::{MatrixRequest, MatrixRequestable};
+use http::Method;
+use std::future::Future;
+use crate::errors::MatrixResult;
+
+/// Contains methods relating to `/user/{userId}/filter` endpoints.
+pub struct Filters;
+
+impl Filters {
+    /// Upload a filter, returning its ID (which can then be given to
+    /// `SyncStream::set_filter`, rather than sending the whole filter each time).
+    pub fn upload<R: MatrixRequestable>(rq: &R, filter: &Filter) -> impl Future<Output = MatrixResult<String>> {
+        let fut = MatrixRequest::new_with_body_ser(
+            Method::POST,
+            format!("/user/{}/filter", rq.get_user_id()),
+            filter.clone()
+        ).send(rq);
+        async move {
+            let rpl: FilterReply = fut.await?;
+            Ok(rpl.filter_id)
+        }
+    }
+    /// Get a filter the user uploaded previously.
+    pub fn get<R: MatrixRequestable>(rq: &R, filter_id: &str) -> impl Future<Output = MatrixResult<Filter>> {
+        MatrixRequest::new_basic(Method::GET, format!("/user/{}/filter/{}", rq.get_user_id(), filter_id))
+            .send(rq)
+    }
+}
@@ -12,16 +12,23 @@
 #![warn(missing_docs)]
 
 extern crate serde;
+#[macro_use] extern crate serde_derive;
 extern crate serde_json;
 pub extern crate http;
+extern crate bytes;
 extern crate hyper;
+extern crate hyper_util;
 extern crate hyper_openssl;
+extern crate http_body_util;
+extern crate openssl;
 extern crate failure;
 extern crate failure_derive;
-extern crate tokio_core;
+extern crate tokio;
 extern crate futures;
 extern crate percent_encoding;
 extern crate uuid;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 pub extern crate gm_types as types;
 
 pub mod errors;
@@ -31,140 +38,321 @@ pub mod sync;
 pub mod profile;
 pub mod media;
 pub mod presence;
-mod util;
+pub mod session;
+pub mod discovery;
+pub mod login;
+pub mod uiaa;
+pub mod register;
+pub mod account;
+pub mod devices;
+pub mod filter;
+pub mod dispatch;
+pub mod state;
+pub mod store;
 
-use util::*;
 use errors::*;
 use types::replies::*;
-use futures::future::Either;
-use hyper::client::{Client, HttpConnector};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
 use http::{Request, Response, Method};
-use hyper_openssl::HttpsConnector;
-use tokio_core::reactor::Handle;
-use futures::*;
-use request::{MatrixRequestable, MatrixRequest};
+use hyper_openssl::client::legacy::HttpsConnector;
+use futures::future::BoxFuture;
+use login::{Credentials, UserIdentifier};
+use request::{MatrixRequestable, MatrixRequest, AccessTokenMode, ApiVersion, RequestTimeout, RetryPolicy};
+use session::Session;
 use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 use uuid::Uuid;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde_json::json;
 
 #[allow(missing_docs)]
-pub type MatrixHyper = Client<HttpsConnector<HttpConnector>>;
+pub type MatrixHyper = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
 /// A connection to a Matrix homeserver, using the `hyper` crate.
+///
+/// This is a cheaply clonable handle: all clones share the same HTTP client
+/// and credentials, and it can be sent between (and shared by) threads and
+/// tasks freely.
+///
+/// All of the futures returned by this crate expect to be run inside a `tokio` runtime.
 #[derive(Clone)]
 pub struct MatrixClient {
+    inner: Arc<MatrixClientInner>,
+    user_id: String
+}
+struct MatrixClientInner {
     hyper: MatrixHyper,
     access_token: String,
-    hdl: Handle,
-    user_id: String,
+    device_id: Option<String>,
     url: String,
-    is_as: bool
+    is_as: bool,
+    access_token_mode: AccessTokenMode,
+    retry_policy: RetryPolicy,
+    request_timeout: Option<Duration>,
+    logout_on_drop: AtomicBool,
+    versions: RwLock<Option<VersionsReply>>
 }
-impl MatrixClient {
-    pub fn new_from_access_token(token: &str, url: &str, hdl: &Handle) -> impl Future<Item = Self, Error = MatrixError> {
-        let conn = match HttpsConnector::new(4) {
-            Ok(c) => c,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        let client = Client::builder()
-            .build(conn);
-
-        let uri: hyper::Uri = match format!("{}/_matrix/client/r0/account/whoami?access_token={}", url, token).parse() {
-            Ok(u) => u,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
+impl MatrixClientInner {
+    fn send_request(&self, req: http::Request<Vec<u8>>) -> MxClientSendRequestFuture {
+        let hyper = self.hyper.clone();
+        let policy = self.retry_policy.clone();
+        let (parts, body) = req.into_parts();
+        let body = Bytes::from(body);
+        let idempotent = request::is_idempotent(&parts.method, parts.uri.path());
+        let timeout = parts.extensions.get::<RequestTimeout>()
+            .map(|t| t.0)
+            .or(self.request_timeout);
 
-        // Build the request
-        let req = Request::builder()
-            .method(Method::GET)
-            .uri(uri)
-            .body(hyper::Body::empty());
-        let req = match req {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
+        MxClientSendRequestFuture {
+            inner: Box::pin(async move {
+                let mut attempt = 1;
+                loop {
+                    let mut req = Request::new(Full::new(body.clone()));
+                    *req.method_mut() = parts.method.clone();
+                    *req.uri_mut() = parts.uri.clone();
+                    *req.headers_mut() = parts.headers.clone();
+                    let fut = async {
+                        let (parts, body) = hyper.request(req).await?.into_parts();
+                        let body = body.collect().await?.to_bytes();
+                        Ok::<_, MatrixError>((parts, body))
+                    };
+                    let (parts, body) = match timeout {
+                        Some(t) => tokio::time::timeout(t, fut).await
+                            .map_err(|_| MatrixError::Timeout)??,
+                        None => fut.await?
+                    };
+                    #[cfg(feature="gitm_show_responses")]
+                    println!("{:#}", String::from_utf8_lossy(&body));
+                    let resp = Response::from_parts(parts, body);
+                    match policy.retry_delay(attempt, idempotent, &resp) {
+                        Some(delay) => {
+                            tokio::time::sleep(delay).await;
+                            attempt += 1;
+                        },
+                        None => return Ok(resp)
+                    }
+                }
+            })
+        }
+    }
+    fn api_version(&self) -> ApiVersion {
+        self.versions.read().unwrap().as_ref()
+            .map(ApiVersion::negotiate)
+            .unwrap_or(ApiVersion::R0)
+    }
+}
+/// Makes requests with a `MatrixClientInner` that isn't part of a `MatrixClient`
+/// yet (for example, while we're still logging in).
+struct PendingClient<'a> {
+    inner: &'a MatrixClientInner
+}
+impl<'a> MatrixRequestable for PendingClient<'a> {
+    type Txnid = Uuid;
+    type ResponseBody = Bytes;
+    type SendRequestFuture = MxClientSendRequestFuture;
 
-        // Send request
-        let resp = client.request(req).map_err(|e| e.into()).and_then(ResponseWrapper::<WhoamiReply>::wrap);
-        let hdl = hdl.clone();
-        let url = url.to_string();
-        let token = token.to_string();
-        Either::A(resp.map(move |rpl| {
-            MatrixClient {
-                hyper: client,
-                access_token: token,
-                user_id: rpl.user_id,
-                url: url,
-                hdl: hdl,
-                is_as: false,
-            }
-        }))
+    fn get_url(&self) -> Cow<'_, str> {
+        (&self.inner.url as &str).into()
+    }
+    fn get_access_token(&self) -> Cow<'_, str> {
+        (&self.inner.access_token as &str).into()
+    }
+    fn access_token_mode(&self) -> AccessTokenMode {
+        self.inner.access_token_mode
+    }
+    fn api_version(&self) -> ApiVersion {
+        self.inner.api_version()
+    }
+    fn get_txnid(&self) -> Uuid {
+        Uuid::new_v4()
+    }
+    fn get_user_id(&self) -> Cow<'_, str> {
+        "".into()
+    }
+    fn send_request(&self, req: http::Request<Vec<u8>>) -> Self::SendRequestFuture {
+        self.inner.send_request(req)
+    }
+}
+/// Options used when making a `MatrixClient`.
+///
+/// The constructors on `MatrixClient` itself use the default options; make one
+/// of these if you want to change any of them, and then call one of the
+/// constructors on here instead.
+#[derive(Clone, Debug)]
+pub struct MatrixClientBuilder {
+    access_token_mode: AccessTokenMode,
+    retry_policy: RetryPolicy,
+    request_timeout: Option<Duration>,
+    logout_on_drop: Option<bool>,
+    versions: Option<VersionsReply>,
+    device_id: Option<String>,
+    initial_device_display_name: Option<String>
+}
+impl Default for MatrixClientBuilder {
+    fn default() -> Self {
+        Self {
+            access_token_mode: AccessTokenMode::Header,
+            retry_policy: RetryPolicy::default(),
+            request_timeout: Some(Duration::from_secs(60)),
+            logout_on_drop: None,
+            versions: None,
+            device_id: None,
+            initial_device_display_name: None
+        }
+    }
+}
+impl MatrixClientBuilder {
+    /// Make a new builder, with the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set how the access token is sent to the homeserver.
+    ///
+    /// The default is `AccessTokenMode::Header`; only change this if the
+    /// homeserver (or something in front of it) doesn't understand the
+    /// `Authorization` header.
+    pub fn access_token_mode(mut self, mode: AccessTokenMode) -> Self {
+        self.access_token_mode = mode;
+        self
+    }
+    /// Set the policy used to retry rate-limited (and otherwise failed) requests.
+    ///
+    /// See the `RetryPolicy` docs for the default.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+    /// Set how long to wait for the response to each request before giving
+    /// up with `MatrixError::Timeout`, or `None` to wait forever.
+    ///
+    /// The default is 60 seconds. This can be overridden for individual
+    /// requests with `MatrixRequest::timeout`; `SyncStream` does this for
+    /// long-polling and initial syncs, and `Media::upload` for uploads, so
+    /// they don't time out early. Each retry (see `retry_policy`) gets a fresh
+    /// timeout.
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+    /// Set whether the client should log out (invalidating its access token)
+    /// when the last clone of it is dropped.
+    ///
+    /// By default, this only happens for sessions the client made itself (i.e.
+    /// by logging in); access tokens given to us, such as with
+    /// `new_from_access_token`, are left alone.
+    ///
+    /// This can be changed later with `MatrixClient::set_logout_on_drop`.
+    pub fn logout_on_drop(mut self, logout: bool) -> Self {
+        self.logout_on_drop = Some(logout);
+        self
+    }
+    /// Tell the client which spec versions and unstable features the homeserver
+    /// supports, instead of asking it (e.g. if you already got them from
+    /// `discover`).
+    ///
+    /// Otherwise, `new_from_access_token` and `login_password` ask the
+    /// homeserver before doing anything else; `restore_session` and `as_new`
+    /// don't, so call `MatrixClient::fetch_server_versions` yourself if you
+    /// need them.
+    pub fn server_versions(mut self, versions: VersionsReply) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+    /// Set the ID of the device to log in as, when logging in.
+    ///
+    /// If this is the ID of an existing device, the homeserver invalidates any
+    /// access token previously given to it. If it isn't set, the homeserver
+    /// makes up a new device ID.
+    pub fn device_id<S: Into<String>>(mut self, id: S) -> Self {
+        self.device_id = Some(id.into());
+        self
+    }
+    /// Set the display name of the device, if logging in makes a new one.
+    pub fn initial_device_display_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.initial_device_display_name = Some(name.into());
+        self
+    }
+    fn make_inner(&self, access_token: String, url: String, is_as: bool) -> MatrixResult<MatrixClientInner> {
+        let conn = HttpsConnector::new()?;
+        let hyper = Client::builder(TokioExecutor::new())
+            .build(conn);
+        Ok(MatrixClientInner {
+            hyper,
+            access_token,
+            device_id: None,
+            url,
+            is_as,
+            access_token_mode: self.access_token_mode,
+            retry_policy: self.retry_policy.clone(),
+            request_timeout: self.request_timeout,
+            logout_on_drop: AtomicBool::new(false),
+            versions: RwLock::new(self.versions.clone())
+        })
+    }
+    /// Fills in the versions the homeserver supports, if we weren't told them.
+    ///
+    /// Failing to get them isn't fatal; we just carry on without them.
+    async fn negotiate_versions(&self, inner: &mut MatrixClientInner) {
+        if self.versions.is_none() {
+            *inner.versions.get_mut().unwrap() = inner.fetch_versions().await.ok();
+        }
+    }
+    /// Make a new client from an existing access token, and check that it's valid by calling
+    /// `/account/whoami`.
+    ///
+    /// ## Parameters
+    ///
+    /// - `token`: the access token to use
+    /// - `url`: the URL of the homeserver
+    pub async fn new_from_access_token(self, token: &str, url: &str) -> MatrixResult<MatrixClient> {
+        let mut inner = self.make_inner(token.to_string(), url.to_string(), false)?;
+        self.negotiate_versions(&mut inner).await;
+        let rpl: WhoamiReply = MatrixRequest::new_basic(Method::GET, "/account/whoami")
+            .send(&PendingClient { inner: &inner })
+            .await?;
+        *inner.logout_on_drop.get_mut() = self.logout_on_drop.unwrap_or(false);
+        inner.device_id = rpl.device_id;
+        Ok(MatrixClient {
+            inner: Arc::new(inner),
+            user_id: rpl.user_id
+        })
     }
     /// Log in to a Matrix homeserver with a username and password, and return a client object.
     ///
     /// ## Parameters
     ///
-    /// - `username`: the username of the account to use (NB: not a MXID)
+    /// - `username`: the username (localpart) or user ID of the account to use
     /// - `password`: the password of the account to use
     /// - `url`: the URL of the homeserver
-    /// - `hdl`: Tokio reactor handle
-    pub fn login_password(username: &str, password: &str, url: &str, hdl: &Handle) -> impl Future<Item = Self, Error = MatrixError> {
-        let conn = match HttpsConnector::new(4) {
-            Ok(c) => c,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        let client = Client::builder()
-            .build(conn);
-        let uri: hyper::Uri = match format!("{}/_matrix/client/r0/login", url).parse() {
-            Ok(u) => u,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-
-        // Build the request
-        let req = Request::builder()
-            .method(Method::POST)
-            .uri(uri)
-            .body(json!({
-                "type": "m.login.password",
-                "user": username,
-                "password": password
-            }).to_string().into());
-        let req = match req {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-
-        // Send request
-        let resp = client.request(req).map_err(|e| e.into()).and_then(ResponseWrapper::<LoginReply>::wrap);
-        let hdl = hdl.clone();
-        let url = url.to_string();
-        Either::A(resp.map(move |rpl| {
-            MatrixClient {
-                hyper: client,
-                access_token: rpl.access_token,
-                user_id: rpl.user_id,
-                url: url,
-                hdl: hdl,
-                is_as: false,
-            }
-        }))
+    ///
+    /// This is a shortcut for calling `login` with `Credentials::Password`; use
+    /// that directly to log in with an email address or phone number instead.
+    pub async fn login_password(self, username: &str, password: &str, url: &str) -> MatrixResult<MatrixClient> {
+        self.login(Credentials::Password {
+            identifier: UserIdentifier::user(username),
+            password: password.to_string()
+        }, url).await
     }
-    /// (for Application Services) Register a user with the given `user_id`.
-    pub fn as_register_user(&mut self, user_id: String) -> impl Future<Item = (), Error = MatrixError> {
-        let body = json!({
-            "type": "m.login.application_service",
-            "user": user_id
-        }).to_string().into_bytes();
-        let req = Request::builder()
-            .method(Method::POST)
-            .uri(format!("{}/_matrix/client/r0/register?access_token={}", self.url, self.access_token))
-            .body(body);
-        let req = match req {
-            Ok(u) => u,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        Either::A(self.typed_api_call(req, true))
+    /// Make a new client from a previously saved `Session`, without checking
+    /// that it's still valid.
+    ///
+    /// Restored sessions aren't logged out on drop by default (see
+    /// `logout_on_drop`).
+    pub fn restore_session(self, session: &Session) -> MatrixResult<MatrixClient> {
+        let mut inner = self.make_inner(session.access_token.clone(), session.homeserver_url.clone(), false)?;
+        *inner.logout_on_drop.get_mut() = self.logout_on_drop.unwrap_or(false);
+        inner.device_id = session.device_id.clone();
+        Ok(MatrixClient {
+            inner: Arc::new(inner),
+            user_id: session.user_id.clone()
+        })
     }
     /// (for Application Services) Make a new AS client.
     ///
@@ -173,131 +361,196 @@ impl MatrixClient {
     /// - `url`: homeserver URL
     /// - `user_id`: user ID to impersonate (can be changed later, using `alter_user_id`)
     /// - `as_token`: application service token
-    /// - `hdl`: Tokio reactor handle
-    pub fn as_new(url: String, user_id: String, as_token: String, hdl: &Handle) -> MatrixResult<Self> {
-        let conn = HttpsConnector::new(4)?;
-        let client = hyper::Client::builder()
-            .build(conn);
-        let hdl = hdl.clone();
+    pub fn as_new(self, url: String, user_id: String, as_token: String) -> MatrixResult<MatrixClient> {
+        let mut inner = self.make_inner(as_token, url, true)?;
+        *inner.logout_on_drop.get_mut() = self.logout_on_drop.unwrap_or(false);
         Ok(MatrixClient {
-            hyper: client,
-            access_token: as_token,
-            user_id: user_id,
-            url: url,
-            hdl: hdl,
-            is_as: true,
+            inner: Arc::new(inner),
+            user_id
         })
     }
-    /// (for Application Services) Alter the user ID which this client is masquerading as.
-    pub fn as_alter_user_id(&mut self, user_id: String) {
-        self.user_id = user_id;
+}
+impl MatrixClient {
+    /// Make a new client from an existing access token, and check that it's valid by calling
+    /// `/account/whoami`.
+    ///
+    /// See `MatrixClientBuilder::new_from_access_token`.
+    pub async fn new_from_access_token(token: &str, url: &str) -> MatrixResult<Self> {
+        MatrixClientBuilder::new().new_from_access_token(token, url).await
     }
-    /// Convenience method that clones the MatrixClient's underlying HTTP client.
-    /// Useful if you don't want to have to make your own HTTP client, and need one
-    /// for some quick'n'dirty task.
-    pub fn get_hyper(&self) -> MatrixHyper {
-        self.hyper.clone()
+    /// Log in to a Matrix homeserver with a username and password, and return a client object.
+    ///
+    /// See `MatrixClientBuilder::login_password`.
+    pub async fn login_password(username: &str, password: &str, url: &str) -> MatrixResult<Self> {
+        MatrixClientBuilder::new().login_password(username, password, url).await
     }
-}
-impl MatrixRequestable for Rc<RefCell<MatrixClient>> {
-    type Txnid = Uuid;
-    type ResponseBody = hyper::Chunk;
-    type ResponseBodyFuture = MxClientResponseBodyFuture;
-    type SendRequestFuture = MxClientSendRequestFuture;
-
-    fn get_url(&self) -> Cow<str> {
-        self.borrow().url.clone().into()
+    /// Make a new client from a previously saved `Session`, without logging in again.
+    ///
+    /// See `MatrixClientBuilder::restore_session`.
+    pub fn restore_session(session: &Session) -> MatrixResult<Self> {
+        MatrixClientBuilder::new().restore_session(session)
     }
-    fn get_access_token(&self) -> Cow<str> {
-        self.borrow().access_token.clone().into()
+    /// Get the ID of the device this client's access token belongs to, if known.
+    pub fn device_id(&self) -> Option<&str> {
+        self.inner.device_id.as_ref().map(|x| x as &str)
     }
-    fn get_txnid(&mut self) -> Uuid {
-        Uuid::new_v4()
+    /// Get the spec versions and unstable features the homeserver supports, if known.
+    pub fn server_versions(&self) -> Option<VersionsReply> {
+        self.inner.versions.read().unwrap().clone()
     }
-    fn get_user_id(&self) -> Cow<str> {
-        self.borrow().user_id.clone().into()
+    /// Whether the homeserver is known to support the given spec version (e.g. `v1.1`).
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.inner.versions.read().unwrap().as_ref()
+            .map(|v| v.supports(version))
+            .unwrap_or(false)
     }
-    fn is_as(&self) -> bool {
-        self.borrow().is_as
+    /// Whether the homeserver is known to have the given unstable feature enabled.
+    pub fn supports_unstable_feature(&self, feature: &str) -> bool {
+        self.inner.versions.read().unwrap().as_ref()
+            .map(|v| v.unstable_feature(feature))
+            .unwrap_or(false)
     }
-    fn send_request(&mut self, req: http::Request<Vec<u8>>) -> Self::SendRequestFuture {
-        let (parts, body) = req.into_parts();
-        let body = hyper::Body::from(body);
-        let req = Request::from_parts(parts, body);
-
-        MxClientSendRequestFuture {
-            inner: self.borrow_mut().hyper.request(req.into())
+    /// Ask the homeserver which spec versions and unstable features it supports
+    /// (again), and remember the answer for `server_versions` and friends.
+    pub async fn fetch_server_versions(&self) -> MatrixResult<VersionsReply> {
+        let versions = self.inner.fetch_versions().await?;
+        *self.inner.versions.write().unwrap() = Some(versions.clone());
+        Ok(versions)
+    }
+    /// Export this client's session, so that it can be saved and restored later
+    /// with `restore_session`.
+    ///
+    /// The `sync_token` of the returned session is always `None`; use
+    /// `SyncStream::session` to get one with it filled in.
+    ///
+    /// If the session was made by logging in, you probably also want to call
+    /// `set_logout_on_drop(false)`, or the session will be useless by the time
+    /// you come to restore it.
+    pub fn session(&self) -> Session {
+        Session {
+            homeserver_url: self.inner.url.clone(),
+            user_id: self.user_id.clone(),
+            device_id: self.inner.device_id.clone(),
+            access_token: self.inner.access_token.clone(),
+            sync_token: None
         }
     }
-}
-/// The `ResponseBodyFuture` of a `MatrixClient`.
-pub struct MxClientResponseBodyFuture {
-    inner: futures::stream::Concat2<hyper::Body>
-}
-impl Future for MxClientResponseBodyFuture {
-    type Item = hyper::Chunk;
-    type Error = MatrixError;
-
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let resp = try_ready!(self.inner.poll());
-        Ok(Async::Ready(resp))
+    /// (for Application Services) Register a user with the given `user_id`.
+    ///
+    /// Normal users should use `MatrixClientBuilder::register` instead.
+    pub async fn as_register_user(&self, user_id: String) -> MatrixResult<()> {
+        MatrixRequest::new_with_body_ser(Method::POST, "/register", json!({
+            "type": "m.login.application_service",
+            "user": user_id
+        })).discarding_send(self).await
+    }
+    /// (for Application Services) Make a new AS client.
+    ///
+    /// See `MatrixClientBuilder::as_new`.
+    pub fn as_new(url: String, user_id: String, as_token: String) -> MatrixResult<Self> {
+        MatrixClientBuilder::new().as_new(url, user_id, as_token)
+    }
+    /// Set whether this client should log out (invalidating its access token)
+    /// when the last clone of it is dropped.
+    ///
+    /// See `MatrixClientBuilder::logout_on_drop` for the default.
+    pub fn set_logout_on_drop(&self, logout: bool) {
+        self.inner.logout_on_drop.store(logout, Ordering::SeqCst);
+    }
+    /// Invalidate this client's access token, logging out the device it belongs to.
+    ///
+    /// The client (and all clones of it) can't be used to make authenticated
+    /// requests afterwards.
+    pub async fn logout(&self) -> MatrixResult<()> {
+        MatrixRequest::new_basic(Method::POST, "/logout")
+            .discarding_send(self).await?;
+        self.set_logout_on_drop(false);
+        Ok(())
+    }
+    /// Invalidate all access tokens for this user, logging out all of their devices
+    /// (including this one).
+    pub async fn logout_all(&self) -> MatrixResult<()> {
+        MatrixRequest::new_basic(Method::POST, "/logout/all")
+            .discarding_send(self).await?;
+        self.set_logout_on_drop(false);
+        Ok(())
+    }
+    /// (for Application Services) Alter the user ID which this client is masquerading as.
+    ///
+    /// This only affects this handle (and clones made from it afterwards), so
+    /// different tasks can masquerade as different users at the same time.
+    pub fn as_alter_user_id(&mut self, user_id: String) {
+        self.user_id = user_id;
+    }
+    /// Convenience method that clones the MatrixClient's underlying HTTP client.
+    /// Useful if you don't want to have to make your own HTTP client, and need one
+    /// for some quick'n'dirty task.
+    pub fn get_hyper(&self) -> MatrixHyper {
+        self.inner.hyper.clone()
     }
 }
 /// The `SendRequestFuture` of a `MatrixClient`.
+///
+/// Resolves once the whole response body has been read.
 pub struct MxClientSendRequestFuture {
-    inner: hyper::client::ResponseFuture
+    inner: BoxFuture<'static, MatrixResult<Response<Bytes>>>
 }
 impl Future for MxClientSendRequestFuture {
-    type Item = Response<MxClientResponseBodyFuture>;
-    type Error = MatrixError;
+    type Output = MatrixResult<Response<Bytes>>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let req: http::Response<_> = try_ready!(self.inner.poll()).into();
-        let (parts, body) = req.into_parts();
-        let body = MxClientResponseBodyFuture {
-            inner: body.concat2()
-        };
-        let resp = Response::from_parts(parts, body);
-        Ok(Async::Ready(resp))
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
     }
 }
 impl MatrixRequestable for MatrixClient {
     type Txnid = Uuid;
-    type ResponseBody = hyper::Chunk;
-    type ResponseBodyFuture = MxClientResponseBodyFuture;
+    type ResponseBody = Bytes;
     type SendRequestFuture = MxClientSendRequestFuture;
 
-    fn get_url(&self) -> Cow<str> {
-        (&self.url as &str).into()
+    fn get_url(&self) -> Cow<'_, str> {
+        (&self.inner.url as &str).into()
+    }
+    fn get_access_token(&self) -> Cow<'_, str> {
+        (&self.inner.access_token as &str).into()
+    }
+    fn access_token_mode(&self) -> AccessTokenMode {
+        self.inner.access_token_mode
     }
-    fn get_access_token(&self) -> Cow<str> {
-        (&self.access_token as &str).into()
+    fn api_version(&self) -> ApiVersion {
+        self.inner.api_version()
     }
-    fn get_txnid(&mut self) -> Uuid {
+    fn get_txnid(&self) -> Uuid {
         Uuid::new_v4()
     }
-    fn get_user_id(&self) -> Cow<str> {
+    fn get_user_id(&self) -> Cow<'_, str> {
         (&self.user_id as &str).into()
     }
     fn is_as(&self) -> bool {
-        self.is_as
+        self.inner.is_as
     }
-    fn send_request(&mut self, req: http::Request<Vec<u8>>) -> Self::SendRequestFuture {
-        let (parts, body) = req.into_parts();
-        let body = hyper::Body::from(body);
-        let req = Request::from_parts(parts, body);
-
-        MxClientSendRequestFuture {
-            inner: self.hyper.request(req.into())
-        }
+    fn send_request(&self, req: http::Request<Vec<u8>>) -> Self::SendRequestFuture {
+        self.inner.send_request(req)
     }
 }
-impl Drop for MatrixClient {
-    /// Invalidates our access token, so we don't have millions of devices.
-    /// Also sets us as offline.
+impl Drop for MatrixClientInner {
+    /// If enabled, invalidates our access token, so we don't have millions of
+    /// devices.
+    ///
+    /// This happens when the last clone of a `MatrixClient` is dropped, by
+    /// spawning a `/logout` request onto the current `tokio` runtime (without
+    /// waiting for it to finish). If we're not inside a runtime, nothing is
+    /// sent, and the access token stays valid.
     fn drop(&mut self) {
+        if !*self.logout_on_drop.get_mut() {
+            return;
+        }
         let fut = MatrixRequest::new_basic(Method::POST, "/logout")
-            .discarding_send(self).map_err(|_| ()).map(|_| ());
-        self.hdl.spawn(fut);
+            .discarding_send(&PendingClient { inner: self });
+        // If we aren't inside a runtime any more, there's nothing to run the request on.
+        if let Ok(hdl) = tokio::runtime::Handle::try_current() {
+            hdl.spawn(async move {
+                let _ = fut.await;
+            });
+        }
     }
 }
@@ -0,0 +1,232 @@
+//! Logging in, with any of the login types the homeserver supports.
+
+use crate::errors::*;
+use crate::request::{ApiType, ApiVersion, MatrixRequest, QUERY_VALUE_ENCODE_SET};
+use crate::request::apis::negotiated::ClientApi;
+use crate::types::replies::{LoginFlow, LoginFlowsReply, LoginReply};
+use crate::{MatrixClient, MatrixClientBuilder, PendingClient};
+use http::Method;
+use percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Identifies the user logging in.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum UserIdentifier {
+    /// A Matrix user ID, or just the localpart of one.
+    #[serde(rename = "m.id.user")]
+    User {
+        /// The user ID or localpart.
+        user: String
+    },
+    /// A third-party identifier bound to the account (e.g. an email address).
+    #[serde(rename = "m.id.thirdparty")]
+    ThirdParty {
+        /// The medium of the identifier (`email` or `msisdn`).
+        medium: String,
+        /// The identifier itself.
+        address: String
+    },
+    /// A phone number bound to the account, in a format the user is likely to
+    /// type it in.
+    #[serde(rename = "m.id.phone")]
+    Phone {
+        /// The two-letter ISO 3166-1 country code the number is from.
+        country: String,
+        /// The phone number.
+        phone: String
+    }
+}
+impl UserIdentifier {
+    /// Identify the user by their user ID or localpart.
+    pub fn user<S: Into<String>>(user: S) -> Self {
+        UserIdentifier::User { user: user.into() }
+    }
+    /// Identify the user by an email address bound to their account.
+    pub fn email<S: Into<String>>(address: S) -> Self {
+        UserIdentifier::ThirdParty { medium: "email".into(), address: address.into() }
+    }
+    /// Identify the user by a phone number bound to their account.
+    pub fn phone<S: Into<String>, T: Into<String>>(country: S, phone: T) -> Self {
+        UserIdentifier::Phone { country: country.into(), phone: phone.into() }
+    }
+}
+/// Credentials for logging in, with `MatrixClientBuilder::login`.
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    /// `m.login.password`: a password.
+    Password {
+        /// Who's logging in.
+        identifier: UserIdentifier,
+        /// Their password.
+        password: String
+    },
+    /// `m.login.token`: a login token, as handed out at the end of SSO (see
+    /// `SsoCallbackListener`).
+    Token(String),
+    /// `m.login.application_service`: an application service logging in as
+    /// one of its users.
+    ApplicationService {
+        /// The user to log in as (which must be in the AS's namespace).
+        identifier: UserIdentifier,
+        /// The application service token.
+        as_token: String
+    }
+}
+impl Credentials {
+    fn body(&self) -> Value {
+        match *self {
+            Credentials::Password { ref identifier, ref password } => json!({
+                "type": "m.login.password",
+                "identifier": identifier,
+                "password": password
+            }),
+            Credentials::Token(ref token) => json!({
+                "type": "m.login.token",
+                "token": token
+            }),
+            Credentials::ApplicationService { ref identifier, .. } => json!({
+                "type": "m.login.application_service",
+                "identifier": identifier
+            })
+        }
+    }
+}
+impl MatrixClientBuilder {
+    /// Ask the homeserver at `url` which ways of logging in it supports.
+    pub async fn login_flows(&self, url: &str) -> MatrixResult<Vec<LoginFlow>> {
+        let mut inner = self.make_inner(String::new(), url.to_string(), false)?;
+        self.negotiate_versions(&mut inner).await;
+        let rpl: LoginFlowsReply = MatrixRequest::new_basic(Method::GET, "/login")
+            .send(&PendingClient { inner: &inner })
+            .await?;
+        Ok(rpl.flows)
+    }
+    /// Log in to the homeserver at `url` with the given credentials, and return
+    /// a client object.
+    ///
+    /// The device ID and display name set on this builder (if any) are sent
+    /// along too.
+    pub async fn login(self, creds: Credentials, url: &str) -> MatrixResult<MatrixClient> {
+        let token = match creds {
+            Credentials::ApplicationService { ref as_token, .. } => as_token.clone(),
+            _ => String::new()
+        };
+        let mut inner = self.make_inner(token, url.to_string(), false)?;
+        self.negotiate_versions(&mut inner).await;
+        let mut body = creds.body();
+        if let Some(ref id) = self.device_id {
+            body["device_id"] = json!(id);
+        }
+        if let Some(ref name) = self.initial_device_display_name {
+            body["initial_device_display_name"] = json!(name);
+        }
+        let rpl: LoginReply = MatrixRequest::new_with_body_ser(Method::POST, "/login", body)
+            .send(&PendingClient { inner: &inner })
+            .await?;
+        inner.access_token = rpl.access_token;
+        inner.device_id = Some(rpl.device_id);
+        *inner.logout_on_drop.get_mut() = self.logout_on_drop.unwrap_or(true);
+        Ok(MatrixClient {
+            inner: Arc::new(inner),
+            user_id: rpl.user_id
+        })
+    }
+    /// Get the URL to send the user's browser to in order to log in with SSO
+    /// at the homeserver at `url`.
+    ///
+    /// Once they've logged in, the homeserver sends them back to `redirect_url`
+    /// with a `loginToken` query parameter, which can be used to log in with
+    /// `Credentials::Token`. `idp_id` picks one of the identity providers from
+    /// `login_flows`; if it's `None`, the homeserver asks the user to choose.
+    ///
+    /// This uses the r0 paths unless the builder was given versions with
+    /// `server_versions` that say the homeserver supports newer ones.
+    pub fn sso_redirect_url(&self, url: &str, redirect_url: &str, idp_id: Option<&str>) -> String {
+        let version = self.versions.as_ref()
+            .map(ApiVersion::negotiate)
+            .unwrap_or(ApiVersion::R0);
+        let mut ret = format!("{}{}/login/sso/redirect", url, ClientApi.get_path_for(version));
+        if let Some(idp) = idp_id {
+            ret += "/";
+            ret.extend(utf8_percent_encode(idp, PATH_SEGMENT_ENCODE_SET));
+        }
+        ret += "?redirectUrl=";
+        ret.extend(utf8_percent_encode(redirect_url, QUERY_VALUE_ENCODE_SET));
+        ret
+    }
+}
+/// A tiny local HTTP server that catches the redirect at the end of SSO login.
+///
+/// Bind one, pass its `redirect_url` to `MatrixClientBuilder::sso_redirect_url`,
+/// send the user to the resulting URL, and then call `wait_for_token` to get
+/// the login token once they're done.
+pub struct SsoCallbackListener {
+    listener: TcpListener,
+    addr: SocketAddr
+}
+impl SsoCallbackListener {
+    /// Listen on `127.0.0.1`, on a port chosen by the OS.
+    pub async fn bind() -> MatrixResult<Self> {
+        Self::bind_to(([127, 0, 0, 1], 0).into()).await
+    }
+    /// Listen on the given address.
+    pub async fn bind_to(addr: SocketAddr) -> MatrixResult<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        Ok(Self { listener, addr })
+    }
+    /// The URL the homeserver should redirect the user to.
+    pub fn redirect_url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+    /// Wait for the user's browser to be redirected here, and return the
+    /// login token it brings with it.
+    ///
+    /// Requests that don't carry a token (such as for a favicon) get a 404,
+    /// and are otherwise ignored.
+    pub async fn wait_for_token(self) -> MatrixResult<String> {
+        loop {
+            let (mut sock, _) = self.listener.accept().await?;
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 16384 {
+                let n = sock.read(&mut chunk).await?;
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let token = login_token(&String::from_utf8_lossy(&buf));
+            let resp: &[u8] = if token.is_some() {
+                b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nLogged in! You can close this window now.\n"
+            }
+            else {
+                b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n"
+            };
+            // The token's the important bit; if the browser's gone away already, who cares.
+            let _ = sock.write_all(resp).await;
+            if let Some(tok) = token {
+                return Ok(tok);
+            }
+        }
+    }
+}
+/// Gets the `loginToken` query parameter out of the request line of an HTTP request.
+fn login_token(req: &str) -> Option<String> {
+    let target = req.lines().next()?.split(' ').nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query.split('&')
+        .filter_map(|kv| {
+            match kv.split_once('=') {
+                Some(("loginToken", v)) => percent_decode(v.as_bytes()).decode_utf8().ok(),
+                _ => None
+            }
+        })
+        .map(|x| x.into_owned())
+        .find(|x| !x.is_empty())
+}
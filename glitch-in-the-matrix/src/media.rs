@@ -1,38 +1,49 @@
 //! Media repository management.
 
-use futures::{self, Future};
+use std::future::Future;
 use crate::request::{self, MatrixRequest, MatrixRequestable};
 use http::Method;
 use std::collections::HashMap;
+use std::time::Duration;
 use http::header::{HeaderValue, CONTENT_TYPE};
 use types::replies::UploadReply;
-use futures::future::Either;
-use crate::errors::MatrixError;
+use crate::errors::MatrixResult;
+
+/// The time allowed for an upload, on top of the time it takes to send the
+/// data.
+const UPLOAD_TIMEOUT_BASE: Duration = Duration::from_secs(60);
+/// The slowest upload speed we allow for.
+const UPLOAD_BYTES_PER_SECOND: usize = 64 * 1024;
 
 /// Contains media repository endpoints.
 pub struct Media;
 
 impl Media {
     /// Upload some data (convertible to a `Body`) of a given `ContentType`, like an image.
-    pub fn upload<T: Into<Vec<u8>>, R: MatrixRequestable>(rq: &mut R, data: T, content_type: &str) -> impl Future<Item = UploadReply, Error = MatrixError> {
+    ///
+    /// Rather than the client's default request timeout, uploads get one
+    /// minute plus one second for every 64 KiB of data, so big files don't
+    /// time out just for being big.
+    pub fn upload<T: Into<Vec<u8>>, R: MatrixRequestable>(rq: &R, data: T, content_type: &str) -> impl Future<Output = MatrixResult<UploadReply>> {
+        let data = data.into();
+        let timeout = UPLOAD_TIMEOUT_BASE + Duration::from_secs((data.len() / UPLOAD_BYTES_PER_SECOND) as u64);
         let req = MatrixRequest {
             meth: Method::POST,
             endpoint: "/upload".into(),
             params: HashMap::new(),
             body: (),
-            typ: request::apis::r0::MediaApi
+            typ: request::apis::negotiated::MediaApi,
+            timeout: Some(timeout)
         }.make_request(rq);
-        let mut req = match req {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        *req.body_mut() = data.into();
-        let hv = match HeaderValue::from_str(content_type) {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        req.headers_mut().insert(CONTENT_TYPE, hv);
-        Either::A(rq.typed_api_call(req, false))
+        let resp = req.and_then(|mut req| {
+            *req.body_mut() = data;
+            let hv = HeaderValue::from_str(content_type)?;
+            req.headers_mut().insert(CONTENT_TYPE, hv);
+            Ok(rq.typed_api_call(req, false))
+        });
+        async move {
+            resp?.await
+        }
     }
 
 }
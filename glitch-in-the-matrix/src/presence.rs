@@ -3,8 +3,8 @@
 use types::content::root::types::Presence;
 use crate::request::{MatrixRequest, MatrixRequestable};
 use http::Method;
-use crate::errors::MatrixError;
-use futures::Future;
+use crate::errors::MatrixResult;
+use std::future::Future;
 use serde_json::json;
 
 /// Contains methods relating to `/presence/` endpoints.
@@ -12,7 +12,7 @@ pub struct PresenceManagement;
 
 impl PresenceManagement {
     /// Update our presence status.
-    pub fn update_presence<R: MatrixRequestable>(rq: &mut R, p: Presence) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn update_presence<R: MatrixRequestable>(rq: &R, p: Presence) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_with_body_ser(
             Method::PUT,
             format!("/presence/{}/status", rq.get_user_id()),
@@ -3,8 +3,8 @@
 use types::replies::DisplaynameReply;
 use crate::request::{MatrixRequest, MatrixRequestable};
 use http::Method;
-use futures::Future;
-use crate::errors::MatrixError;
+use std::future::Future;
+use crate::errors::MatrixResult;
 
 /// Contains methods relating to `/profile/` endpoints.
 pub struct Profile;
@@ -12,12 +12,12 @@ pub struct Profile;
 impl Profile {
     /// Get the displayname of a given user ID. This API may be used to fetch the user's own displayname or
     /// to query the name of other users; either locally or on remote homeservers.
-    pub fn get_displayname<R: MatrixRequestable>(rq: &mut R, user_id: &str) -> impl Future<Item = DisplaynameReply, Error = MatrixError> {
+    pub fn get_displayname<R: MatrixRequestable>(rq: &R, user_id: &str) -> impl Future<Output = MatrixResult<DisplaynameReply>> {
         MatrixRequest::new_basic(Method::GET, format!("/profile/{}/displayname", user_id))
             .send(rq)
     }
     /// Sets the user's displayname.
-    pub fn set_displayname<R: MatrixRequestable>(rq: &mut R, name: String) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn set_displayname<R: MatrixRequestable>(rq: &R, name: String) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_with_body_ser(
             Method::PUT,
             format!("/profile/{}/displayname", rq.get_user_id()),
@@ -0,0 +1,91 @@
+//! Registering new (non-application service) accounts.
+
+use crate::errors::*;
+use crate::request::MatrixRequest;
+use crate::types::replies::{LoginReply, RequestTokenReply, UiaaInfo};
+use crate::uiaa::{AuthData, UiaaRequest, UiaaResponse};
+use crate::{MatrixClient, MatrixClientBuilder, MatrixClientInner, PendingClient};
+use http::Method;
+use serde_json::json;
+use std::sync::Arc;
+
+/// An account registration in progress, made with `MatrixClientBuilder::register`.
+///
+/// Registration is protected by User-Interactive Authentication: call `submit`
+/// with no `AuthData` to find out which stages the homeserver wants, and then
+/// once for each stage until it returns a logged-in client.
+pub struct Registration {
+    builder: MatrixClientBuilder,
+    inner: MatrixClientInner,
+    req: UiaaRequest<'static>
+}
+impl MatrixClientBuilder {
+    /// Start registering a new account on the homeserver at `url`.
+    ///
+    /// If `username` (the localpart of the new user ID) is `None`, the
+    /// homeserver picks one. The device ID and display name set on this
+    /// builder (if any) are used for the new account's first device.
+    pub async fn register(self, username: Option<&str>, password: &str, url: &str) -> MatrixResult<Registration> {
+        let mut inner = self.make_inner(String::new(), url.to_string(), false)?;
+        self.negotiate_versions(&mut inner).await;
+        let mut body = json!({
+            "password": password
+        });
+        if let Some(user) = username {
+            body["username"] = json!(user);
+        }
+        if let Some(ref id) = self.device_id {
+            body["device_id"] = json!(id);
+        }
+        if let Some(ref name) = self.initial_device_display_name {
+            body["initial_device_display_name"] = json!(name);
+        }
+        let mut req = MatrixRequest::new_with_body_ser(Method::POST, "/register", body);
+        req.params.insert("kind".into(), "user".into());
+        Ok(Registration {
+            builder: self,
+            inner,
+            req: UiaaRequest::new(req)
+        })
+    }
+}
+impl Registration {
+    /// Get what the homeserver last told us about the authentication it wants,
+    /// if we've asked yet.
+    pub fn info(&self) -> Option<&UiaaInfo> {
+        self.req.info()
+    }
+    /// Ask the homeserver to email a validation link to `email`, for the
+    /// `m.login.email.identity` stage.
+    ///
+    /// `client_secret` is a secret you make up, which must be passed along
+    /// with the returned session ID in the `AuthData`; `send_attempt` should
+    /// be incremented to send the email again.
+    pub async fn request_email_token(&self, email: &str, client_secret: &str, send_attempt: u32) -> MatrixResult<RequestTokenReply> {
+        MatrixRequest::new_with_body_ser(Method::POST, "/register/email/requestToken", json!({
+            "email": email,
+            "client_secret": client_secret,
+            "send_attempt": send_attempt
+        })).send(&PendingClient { inner: &self.inner }).await
+    }
+    /// Complete a stage of the registration (or, if `auth` is `None`, find out
+    /// which stages are needed).
+    ///
+    /// Once all the stages are done, the account is made, and this returns a
+    /// client logged in to it (which logs out on drop, unless the builder said
+    /// otherwise).
+    pub async fn submit(&mut self, auth: Option<AuthData>) -> MatrixResult<UiaaResponse<MatrixClient>> {
+        let rpl: LoginReply = match self.req.send(&PendingClient { inner: &self.inner }, auth).await? {
+            UiaaResponse::Done(rpl) => rpl,
+            UiaaResponse::AuthRequired(info) => return Ok(UiaaResponse::AuthRequired(info))
+        };
+        let mut inner = self.builder.make_inner(rpl.access_token, self.inner.url.clone(), false)?;
+        *inner.versions.get_mut().unwrap() = self.inner.versions.read().unwrap().clone();
+        inner.device_id = Some(rpl.device_id);
+        *inner.logout_on_drop.get_mut() = self.builder.logout_on_drop.unwrap_or(true);
+        Ok(UiaaResponse::Done(MatrixClient {
+            inner: Arc::new(inner),
+            user_id: rpl.user_id
+        }))
+    }
+}
@@ -4,25 +4,149 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use http::{Request, Response, Method};
-use futures::future::Either;
+use http::{Request, Response, Method, StatusCode, HeaderMap};
+use http::header::{AUTHORIZATION, RETRY_AFTER};
 use crate::errors::{MatrixError, MatrixResult};
-use types::replies::BadRequestReply;
+use types::replies::{BadRequestReply, UiaaInfo, VersionsReply};
 use serde_json;
-use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
-use futures::{self, Future, Poll, Async, try_ready};
+use percent_encoding::{utf8_percent_encode, EncodeSet, USERINFO_ENCODE_SET};
+use futures::ready;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 /// Describes the type of a Matrix API.
 pub trait ApiType {
     /// Get the base path which all requests to this API should contain.
     ///
-    /// For example, `ClientApi`, the struct for the client-server API, sets
+    /// For example, `r0::ClientApi`, the struct for the client-server API, sets
     /// this method to return `/_matrix/client/r0`
     fn get_path<'a>(&'a self) -> Cow<'a, str>;
+    /// Get the base path to use when talking to a homeserver that we've
+    /// negotiated the given `ApiVersion` with.
+    ///
+    /// By default, this just calls `get_path`; only the `apis::negotiated`
+    /// types actually pay attention to the version.
+    fn get_path_for<'a>(&'a self, _version: ApiVersion) -> Cow<'a, str> {
+        self.get_path()
+    }
+}
+/// Which set of paths to use for the versioned Matrix APIs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// `r0` paths (e.g. `/_matrix/client/r0`), supported by all homeservers
+    /// up to spec version `r0.6.1`, and deprecated since.
+    R0,
+    /// `v3` paths (e.g. `/_matrix/client/v3`), supported by homeservers
+    /// implementing spec version `v1.1` or later.
+    V3
+}
+impl ApiVersion {
+    /// Pick the newest paths a homeserver supports, given its reply to `/versions`.
+    pub fn negotiate(versions: &VersionsReply) -> Self {
+        if versions.versions.iter().any(|v| v.starts_with("v1.")) {
+            ApiVersion::V3
+        }
+        else {
+            ApiVersion::R0
+        }
+    }
 }
 /// Types of Matrix APIs.
 pub mod apis {
+    use crate::request::ApiType;
+    use std::borrow::Cow;
+
+    /// `/_matrix/client`, for the few client-server API endpoints that aren't
+    /// versioned (e.g. `/versions`).
+    pub struct UnversionedClientApi;
+    impl ApiType for UnversionedClientApi {
+        fn get_path(&self) -> Cow<'static, str> {
+            "/_matrix/client".into()
+        }
+    }
+    /// `/.well-known/matrix`, used for server discovery.
+    pub struct WellKnownApi;
+    impl ApiType for WellKnownApi {
+        fn get_path(&self) -> Cow<'static, str> {
+            "/.well-known/matrix".into()
+        }
+    }
+    /// APIs at whichever version the homeserver supports best (see `ApiVersion`).
+    ///
+    /// These fall back to r0 if the client doesn't know which versions the
+    /// homeserver supports.
+    pub mod negotiated {
+        use crate::request::{ApiType, ApiVersion};
+        use std::borrow::Cow;
+        /// `/_matrix/client/r0` or `/_matrix/client/v3`
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                super::r0::ClientApi.get_path()
+            }
+            fn get_path_for(&self, version: ApiVersion) -> Cow<'static, str> {
+                match version {
+                    ApiVersion::R0 => super::r0::ClientApi.get_path(),
+                    ApiVersion::V3 => super::v3::ClientApi.get_path()
+                }
+            }
+        }
+        /// `/_matrix/media/r0` or `/_matrix/media/v3`
+        pub struct MediaApi;
+        impl ApiType for MediaApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                super::r0::MediaApi.get_path()
+            }
+            fn get_path_for(&self, version: ApiVersion) -> Cow<'static, str> {
+                match version {
+                    ApiVersion::R0 => super::r0::MediaApi.get_path(),
+                    ApiVersion::V3 => super::v3::MediaApi.get_path()
+                }
+            }
+        }
+    }
+    /// APIs at version v3.
+    pub mod v3 {
+        use crate::request::ApiType;
+        use std::borrow::Cow;
+        /// `/_matrix/client/v3`
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/client/v3".into()
+            }
+        }
+        /// `/_matrix/media/v3`
+        pub struct MediaApi;
+        impl ApiType for MediaApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/media/v3".into()
+            }
+        }
+    }
+    /// Unstable APIs, for endpoints from MSCs that haven't made it into a
+    /// spec release yet.
+    pub mod unstable {
+        use crate::request::ApiType;
+        use std::borrow::Cow;
+        /// `/_matrix/client/unstable`
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/client/unstable".into()
+            }
+        }
+        /// `/_matrix/media/unstable`
+        pub struct MediaApi;
+        impl ApiType for MediaApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/media/unstable".into()
+            }
+        }
+    }
     /// APIs at version r0.
     pub mod r0 {
         use crate::request::ApiType;
@@ -45,42 +169,40 @@ pub mod apis {
 }
 /// Future representing a response to a Matrix API call that isn't ready yet.
 ///
-/// Returned by the `typed_api_response` method on a `MatrixRequestable`.
-pub struct TypedApiResponse<T, U, V> {
-    response: Option<U>,
-    body: Option<V>,
-    parts: Option<::http::response::Parts>,
-    _ph: PhantomData<T>,
+/// Returned by the `typed_api_call` method on a `MatrixRequestable`.
+pub struct TypedApiResponse<T, U> {
+    response: Pin<Box<U>>,
+    _ph: PhantomData<fn() -> T>,
     discard: bool
 }
-impl<T, U, RB, V> Future for TypedApiResponse<T, U, V>
+impl<T, U, RB> Future for TypedApiResponse<T, U>
     where T: DeserializeOwned + 'static,
           RB: AsRef<[u8]>,
-          V: Future<Item = RB, Error = MatrixError>,
-          U: Future<Item = Response<V>, Error = MatrixError> {
+          U: Future<Output = MatrixResult<Response<RB>>> {
 
-    type Item = T;
-    type Error = MatrixError;
+    type Output = MatrixResult<T>;
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         use std::any::TypeId;
         use std::ptr;
 
-        if self.response.is_some() {
-            let resp = try_ready!(self.response.as_mut().unwrap().poll());
-            self.response = None;
-            let (parts, body) = resp.into_parts();
-            self.parts = Some(parts);
-            self.body = Some(body);
-        }
-        let body = try_ready!(self.body.as_mut().unwrap().poll());
-        let parts = self.parts.take().unwrap();
+        let resp = ready!(self.response.as_mut().poll(cx))?;
+        let (parts, body) = resp.into_parts();
         if !parts.status.is_success() {
-            if let Ok(e) = ::serde_json::from_slice::<BadRequestReply>(body.as_ref()) {
-                return Err(MatrixError::BadRequest(e));
+            if parts.status == StatusCode::UNAUTHORIZED {
+                if let Ok(info) = ::serde_json::from_slice::<UiaaInfo>(body.as_ref()) {
+                    return Poll::Ready(Err(MatrixError::AuthRequired(Box::new(info))));
+                }
+            }
+            if let Ok(mut e) = ::serde_json::from_slice::<BadRequestReply>(body.as_ref()) {
+                if e.retry_after_ms.is_none() && parts.status == StatusCode::TOO_MANY_REQUESTS {
+                    e.retry_after_ms = retry_after(&parts.headers, &[])
+                        .map(|d| d.as_millis() as u64);
+                }
+                return Poll::Ready(Err(MatrixError::BadRequest(e)));
             }
             else {
-                return Err(MatrixError::HttpCode(parts.status));
+                return Poll::Ready(Err(MatrixError::HttpCode(parts.status)));
             }
         }
         let data = if TypeId::of::<T>() == TypeId::of::<()>() && self.discard {
@@ -94,62 +216,202 @@ impl<T, U, RB, V> Future for TypedApiResponse<T, U, V>
         else {
             ::serde_json::from_slice::<T>(body.as_ref())?
         };
-        Ok(Async::Ready(data))
+        Poll::Ready(Ok(data))
     }
 }
+/// Everything that can't go in a query string key or value unescaped.
+#[derive(Copy, Clone, Debug)]
+#[allow(non_camel_case_types)]
+pub(crate) struct QUERY_VALUE_ENCODE_SET;
+impl EncodeSet for QUERY_VALUE_ENCODE_SET {
+    fn contains(&self, byte: u8) -> bool {
+        match byte {
+            b'&' | b'+' | b'%' => true,
+            _ => USERINFO_ENCODE_SET.contains(byte)
+        }
+    }
+}
+/// How long to wait for the response to a request; see `MatrixRequest::timeout`.
+///
+/// This is attached to requests made by `MatrixRequest` as an extension (see
+/// `http::Request::extensions`), for the `MatrixRequestable` to pick up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RequestTimeout(pub Duration);
+/// How the access token is sent to the homeserver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessTokenMode {
+    /// In an `Authorization: Bearer` header.
+    ///
+    /// This is the default, as it keeps the token out of any request logs.
+    Header,
+    /// In the `access_token` query-string parameter.
+    QueryString
+}
+/// Controls if, and how, a client retries requests that fail in a way that
+/// might succeed if tried again later.
+///
+/// Requests are retried if the homeserver rate-limited them (`M_LIMIT_EXCEEDED`,
+/// HTTP 429), or (if `retry_server_errors` is set) if they're idempotent (see
+/// `is_idempotent`) and failed with a 5xx status.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of times a request will be sent, including the first
+    /// attempt. Setting this to 1 disables retrying.
+    pub max_attempts: u32,
+    /// Whether to retry idempotent requests that failed with a 5xx HTTP
+    /// status.
+    ///
+    /// Other requests are never retried after a 5xx, as the homeserver might
+    /// have acted on them anyway (e.g. if the error came from a proxy in front
+    /// of it), and doing it again could make duplicate rooms, devices, etc.
+    pub retry_server_errors: bool,
+    /// How long to wait before the first retry of a failed request, if the
+    /// server didn't say how long to wait. This is doubled on each attempt, up
+    /// to `max_delay`.
+    pub base_delay: Duration,
+    /// The longest we'll wait before retrying.
+    ///
+    /// If the homeserver asks us to wait longer than this, the request isn't
+    /// retried at all, and the error is returned instead.
+    pub max_delay: Duration
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            retry_server_errors: true,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30)
+        }
+    }
+}
+impl RetryPolicy {
+    /// A policy that never retries anything.
+    pub fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+    /// Decide whether a request should be retried, given the response to
+    /// attempt number `attempt` (starting from 1), and whether the request is
+    /// idempotent (see `is_idempotent`).
+    ///
+    /// Returns the amount of time to wait before trying again, or `None` if the
+    /// response should be returned as-is.
+    pub fn retry_delay<RB: AsRef<[u8]>>(&self, attempt: u32, idempotent: bool, resp: &Response<RB>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        let status = resp.status();
+        let backoff = self.base_delay
+            .checked_mul(2u32.saturating_pow(attempt - 1))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            match retry_after(resp.headers(), resp.body().as_ref()) {
+                Some(delay) if delay > self.max_delay => None,
+                Some(delay) => Some(delay),
+                None => Some(backoff)
+            }
+        }
+        else if status.is_server_error() && self.retry_server_errors && idempotent {
+            Some(backoff)
+        }
+        else {
+            None
+        }
+    }
+}
+/// Whether sending a request to the given path more than once has the same
+/// effect as sending it once, so it's safe to retry if we don't know whether
+/// it worked.
+///
+/// This is true for `GET` and `HEAD` requests, and for `PUT` and `DELETE`
+/// requests with a transaction ID (i.e. sending events and to-device
+/// messages, and redacting events), which the homeserver deduplicates.
+pub fn is_idempotent(meth: &Method, path: &str) -> bool {
+    match *meth {
+        Method::GET | Method::HEAD => true,
+        Method::PUT | Method::DELETE => ["/send/", "/redact/", "/sendToDevice/"].iter()
+            .any(|seg| path.contains(seg)),
+        _ => false
+    }
+}
+/// Works out how long a rate-limited response asked us to wait, from either
+/// the `retry_after_ms` field of the error or the `Retry-After` header.
+fn retry_after(headers: &HeaderMap, body: &[u8]) -> Option<Duration> {
+    ::serde_json::from_slice::<BadRequestReply>(body).ok()
+        .and_then(|brr| brr.retry_after_ms)
+        .map(Duration::from_millis)
+        .or_else(|| {
+            headers.get(RETRY_AFTER)?
+                .to_str().ok()?
+                .parse().ok()
+                .map(Duration::from_secs)
+        })
+}
 /// Represents an object that can make requests to the Matrix Client-Server API.
 pub trait MatrixRequestable {
     /// The type of the transaction ID returned by `get_txnid()`.
     type Txnid: ::std::fmt::Display;
     /// The type of the HTTP response body.
     type ResponseBody: AsRef<[u8]>;
-    /// The type of the future returned as a HTTP response body.
-    ///
-    /// This is polled in order to get the response body.
-    type ResponseBodyFuture: Future<Item = Self::ResponseBody, Error = MatrixError> + 'static;
     /// The type of the future returned by `send_request()`.
     ///
-    /// Should return a HTTP `Response` with the body being `Self::ResponseBodyFuture`.
-    type SendRequestFuture: Future<Item = Response<Self::ResponseBodyFuture>, Error = MatrixError> + 'static;
+    /// Should resolve to a HTTP `Response`, with the body already read in full.
+    type SendRequestFuture: Future<Output = MatrixResult<Response<Self::ResponseBody>>> + Send + 'static;
     /// Gets the client's URL.
-    fn get_url(&self) -> Cow<str>;
+    fn get_url(&self) -> Cow<'_, str>;
     /// Checks whether the client is an Application Service (AS).
     fn is_as(&self) -> bool { false }
     /// Gets the client's access token.
-    fn get_access_token(&self) -> Cow<str>;
+    ///
+    /// If this is empty, no access token will be sent.
+    fn get_access_token(&self) -> Cow<'_, str>;
+    /// Gets how the client's access token should be sent to the homeserver.
+    fn access_token_mode(&self) -> AccessTokenMode { AccessTokenMode::Header }
+    /// Gets which set of paths to use for the versioned APIs.
+    ///
+    /// Implementors that know which versions the homeserver supports should
+    /// use `ApiVersion::negotiate`; the default is `ApiVersion::R0`.
+    fn api_version(&self) -> ApiVersion { ApiVersion::R0 }
     /// Gets the client's user ID.
-    fn get_user_id(&self) -> Cow<str>;
+    fn get_user_id(&self) -> Cow<'_, str>;
     /// Gets a new transaction ID.
     ///
     /// Implementors should generate a unique ID, as this will be used by the server to ensure
     /// idempotency of requests.
-    fn get_txnid(&mut self) -> Self::Txnid;
+    fn get_txnid(&self) -> Self::Txnid;
     /// Send an arbitrary HTTP request to the Matrix homeserver.
-    fn send_request(&mut self, req: Request<Vec<u8>>) -> Self::SendRequestFuture;
+    ///
+    /// If the request has a `RequestTimeout` extension, implementors should
+    /// give up with `MatrixError::Timeout` if the response takes longer than
+    /// that to arrive.
+    fn send_request(&self, req: Request<Vec<u8>>) -> Self::SendRequestFuture;
 
     /// Send an arbitrary HTTP request to the Matrix homeserver, and deserialize the JSON response
     /// to a value of type T.
     ///
     /// If T is `()`, and `discard` is true, discards the response and returns `()`, no matter what
     /// the server responds with.
-    fn typed_api_call<T>(&mut self, req: Request<Vec<u8>>, discard: bool) -> TypedApiResponse<T, Self::SendRequestFuture, Self::ResponseBodyFuture> where T: DeserializeOwned + 'static {
+    fn typed_api_call<T>(&self, req: Request<Vec<u8>>, discard: bool) -> TypedApiResponse<T, Self::SendRequestFuture> where T: DeserializeOwned + 'static {
         TypedApiResponse {
-            response: Some(self.send_request(req)),
-            body: None,
-            parts: None,
+            response: Box::pin(self.send_request(req)),
             discard,
             _ph: PhantomData
         }
     }
 }
 
-use self::apis::r0::*;
+use self::apis::negotiated::*;
 /// A arbitrary request to an endpoint in the Matrix API.
 ///
 /// To actually determine what URL is used for the request, two things are
 /// consulted: the request type, and the request endpoint. The request type
 /// specifies what Matrix API is being used (for example, the client-server API
-/// revision 0, under `/_matrix/client/r0`), while the endpoint determines what
+/// under `/_matrix/client/r0` or `/_matrix/client/v3`, depending on what
+/// the homeserver supports), while the endpoint determines what
 /// method is being called on that API.
 ///
 /// This type has Super `Cow` Powers.
@@ -166,7 +428,10 @@ pub struct MatrixRequest<'a, T, U = ClientApi> {
     /// requests with no body should use `()` here.
     pub body: T,
     /// Request type.
-    pub typ: U
+    pub typ: U,
+    /// How long to wait for a response before giving up with
+    /// `MatrixError::Timeout`, or `None` to use the client's default.
+    pub timeout: Option<Duration>
 }
 impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
     /// Make a new `MatrixRequest`, specifying all possible options.
@@ -176,7 +441,8 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
             endpoint: endpoint.into(),
             params: HashMap::new(),
             body,
-            typ
+            typ,
+            timeout: None
         }
     }
 }
@@ -186,14 +452,15 @@ impl<'a> MatrixRequest<'a, ()> {
     /// - `meth` and `endpoint` specified
     /// - `params` set to an empty hashmap
     /// - `body` set to ()
-    /// - `typ` set to `apis::r0::ClientApi`
+    /// - `typ` set to `apis::negotiated::ClientApi`
     pub fn new_basic<S: Into<Cow<'a, str>>>(meth: Method, endpoint: S) -> Self {
         Self {
             meth,
             endpoint: endpoint.into(),
             params: HashMap::new(),
             body: (),
-            typ: ClientApi
+            typ: ClientApi,
+            timeout: None
         }
     }
 }
@@ -203,7 +470,7 @@ impl<'a, 'b, 'c> MatrixRequest<'a, HashMap<Cow<'b, str>, Cow<'c, str>>> {
     /// - `meth` and `endpoint` specified
     /// - `body` converted from an iterator over `(T, U)` where T & U implement `Into<Cow<str>>`
     /// - `params` set to an empty hashmap
-    /// - `typ` set to `apis::r0::ClientApi`
+    /// - `typ` set to `apis::negotiated::ClientApi`
     pub fn new_with_body<S, T, U, V>(meth: Method, endpoint: S, body: V) -> Self
         where S: Into<Cow<'a, str>>,
               T: Into<Cow<'b, str>>,
@@ -216,7 +483,8 @@ impl<'a, 'b, 'c> MatrixRequest<'a, HashMap<Cow<'b, str>, Cow<'c, str>>> {
             endpoint: endpoint.into(),
             params: HashMap::new(),
             body,
-            typ: ClientApi
+            typ: ClientApi,
+            timeout: None
         }
     }
 }
@@ -229,7 +497,8 @@ impl<'a, T> MatrixRequest<'a, T> where T: Serialize {
             endpoint: endpoint.into(),
             params: HashMap::new(),
             body,
-            typ: ClientApi
+            typ: ClientApi,
+            timeout: None
         }
     }
 }
@@ -246,44 +515,59 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
     /// Make this `MatrixRequest` into a HTTP request.
     pub fn make_request<C>(&self, client: &C) -> MatrixResult<Request<Vec<u8>>> where C: MatrixRequestable {
         let body = self.body()?;
-        let mut params = format!("access_token={}", client.get_access_token());
+        let token = client.get_access_token();
+        let mode = client.access_token_mode();
+        let mut params = vec![];
+        if !token.is_empty() && mode == AccessTokenMode::QueryString {
+            params.push(format!("access_token={}", utf8_percent_encode(&token, QUERY_VALUE_ENCODE_SET)));
+        }
         if client.is_as() {
-            params += &format!("&user_id={}",
-                              utf8_percent_encode(&client.get_user_id(), DEFAULT_ENCODE_SET));
+            params.push(format!("user_id={}",
+                                utf8_percent_encode(&client.get_user_id(), QUERY_VALUE_ENCODE_SET)));
         }
         for (k, v) in self.params.iter() {
-            params += &format!("&{}={}",
-                              utf8_percent_encode(k.as_ref(), DEFAULT_ENCODE_SET),
-                              utf8_percent_encode(v.as_ref(), DEFAULT_ENCODE_SET));
-        }
-        let url = format!("{}{}{}?{}",
-                          client.get_url(),
-                          self.typ.get_path(),
-                          self.endpoint,
-                          params);
-        let req = Request::builder()
+            params.push(format!("{}={}",
+                                utf8_percent_encode(k.as_ref(), QUERY_VALUE_ENCODE_SET),
+                                utf8_percent_encode(v.as_ref(), QUERY_VALUE_ENCODE_SET)));
+        }
+        let mut url = format!("{}{}{}",
+                              client.get_url(),
+                              self.typ.get_path_for(client.api_version()),
+                              self.endpoint);
+        if !params.is_empty() {
+            url += "?";
+            url += &params.join("&");
+        }
+        let mut req = Request::builder()
             .method(self.meth.clone())
-            .uri(url)
-            .body(body)?;
-        Ok(req)
+            .uri(url);
+        if !token.is_empty() && mode == AccessTokenMode::Header {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        if let Some(timeout) = self.timeout {
+            req = req.extension(RequestTimeout(timeout));
+        }
+        Ok(req.body(body)?)
     }
     /// Sends this request to a Matrix homeserver, expecting a deserializable
     /// `R` return type.
     ///
     /// A helpful mix of `make_hyper()` and `MatrixClient::send_request()`.
-    pub fn send<C, R>(&self, mxc: &mut C) -> impl Future<Item = R, Error = MatrixError> + 'static where R: DeserializeOwned + 'static, C: MatrixRequestable {
-        let req = match self.make_request(mxc) {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        Either::A(mxc.typed_api_call(req, false))
+    ///
+    /// The returned future doesn't borrow from either this request or the client.
+    pub fn send<C, R>(&self, mxc: &C) -> impl Future<Output = MatrixResult<R>> + 'static where R: DeserializeOwned + 'static, C: MatrixRequestable {
+        let resp = self.make_request(mxc)
+            .map(|req| mxc.typed_api_call(req, false));
+        async move {
+            resp?.await
+        }
     }
-    /// Like `send()`, but uses `MatrixClient::send_discarding_request()`.
-    pub fn discarding_send<'x, 'y, C>(&'x self, mxc: &'y mut C) -> impl Future<Item = (), Error = MatrixError> + 'static where C: MatrixRequestable {
-        let req = match self.make_request(mxc) {
-            Ok(r) => r,
-            Err(e) => return Either::B(futures::future::err(e.into()))
-        };
-        Either::A(mxc.typed_api_call(req, true))
+    /// Like `send()`, but discards the response body (see `MatrixRequestable::typed_api_call()`).
+    pub fn discarding_send<C>(&self, mxc: &C) -> impl Future<Output = MatrixResult<()>> + 'static where C: MatrixRequestable {
+        let resp = self.make_request(mxc)
+            .map(|req| mxc.typed_api_call(req, true));
+        async move {
+            resp?.await
+        }
     }
 }
@@ -1,13 +1,16 @@
 //! Abstractions for Matrix rooms.
 
 use types::replies::*;
-use types::messages::Message;
+use types::messages::{InReplyTo, Message};
 use types::events::Event;
+use types::content::Content;
 use types::content::room::PowerLevels;
 use crate::request::{MatrixRequestable, MatrixRequest};
+use crate::store::{PendingTransaction, StateStore};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use futures::*;
+use std::future::Future;
+use std::sync::Arc;
 use crate::errors::*;
 use http::Method;
 
@@ -25,7 +28,7 @@ pub trait RoomExt<'a> {
     ///
     /// If you want to do pretty much anything *with* this `Room`, you probably
     /// want to call this at some point.
-    fn cli<'b, 'c, T>(&'b self, cli: &'c mut T) -> RoomClient<'b, 'a, 'c, T> where T: MatrixRequestable;
+    fn cli<'b, 'c, T>(&'b self, cli: &'c T) -> RoomClient<'b, 'a, 'c, T> where T: MatrixRequestable;
 }
 /// Contains endpoints relating to creating or joining rooms.
 pub struct NewRoom;
@@ -34,22 +37,31 @@ impl NewRoom {
     ///
     /// The server will use the federation API to resolve the alias if the
     /// domain part of the alias does not correspond to the server's own domain.
-    pub fn from_alias<R: MatrixRequestable>(cli: &mut R, alias: &str) -> impl Future<Item = Room<'static>, Error = MatrixError> {
-        MatrixRequest::new_basic(Method::GET, format!("/directory/room/{}", alias))
-            .send(cli)
-            .map(|RoomAliasReply { room, .. }| room)
+    pub fn from_alias<R: MatrixRequestable>(cli: &R, alias: &str) -> impl Future<Output = MatrixResult<Room<'static>>> {
+        let fut = MatrixRequest::new_basic(Method::GET, format!("/directory/room/{}", alias))
+            .send(cli);
+        async move {
+            let RoomAliasReply { room, .. } = fut.await?;
+            Ok(room)
+        }
     }
     /// Joins a room by identifier or alias.
-    pub fn join<R: MatrixRequestable>(cli: &mut R, room: &str) -> impl Future<Item = Room<'static>, Error = MatrixError> {
-        MatrixRequest::new_basic(Method::POST, format!("/join/{}", room))
-            .send(cli)
-            .map(|JoinReply { room }| room)
+    pub fn join<R: MatrixRequestable>(cli: &R, room: &str) -> impl Future<Output = MatrixResult<Room<'static>>> {
+        let fut = MatrixRequest::new_basic(Method::POST, format!("/join/{}", room))
+            .send(cli);
+        async move {
+            let JoinReply { room } = fut.await?;
+            Ok(room)
+        }
     }
     /// Creates a room, with given options.
-    pub fn create<R: MatrixRequestable>(cli: &mut R, opts: RoomCreationOptions) -> impl Future<Item = Room<'static>, Error = MatrixError> {
-        MatrixRequest::new_with_body_ser(Method::POST, "/createRoom", opts)
-            .send(cli)
-            .map(|JoinReply { room }| room)
+    pub fn create<R: MatrixRequestable>(cli: &R, opts: RoomCreationOptions) -> impl Future<Output = MatrixResult<Room<'static>>> {
+        let fut = MatrixRequest::new_with_body_ser(Method::POST, "/createRoom", opts)
+            .send(cli);
+        async move {
+            let JoinReply { room } = fut.await?;
+            Ok(room)
+        }
     }
 }
 /// A `Room` with a `MatrixRequestable` type, which you can use to call endpoints relating
@@ -58,10 +70,10 @@ pub struct RoomClient<'a, 'b: 'a, 'c, T: 'c> {
     /// A reference to a room.
     pub room: &'a Room<'b>,
     /// A reference to a `MatrixRequestable` type.
-    pub cli: &'c mut T
+    pub cli: &'c T
 }
 impl<'a> RoomExt<'a> for Room<'a> {
-   fn cli<'b, 'c, T>(&'b self, cli: &'c mut T) -> RoomClient<'b, 'a, 'c, T> where T: MatrixRequestable {
+   fn cli<'b, 'c, T>(&'b self, cli: &'c T) -> RoomClient<'b, 'a, 'c, T> where T: MatrixRequestable {
         RoomClient {
             room: self,
             cli
@@ -70,7 +82,7 @@ impl<'a> RoomExt<'a> for Room<'a> {
 }
 impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// Sends a message to this room.
-    pub fn send(&mut self, msg: Message) -> impl Future<Item = SendReply, Error = MatrixError> {
+    pub fn send(&self, msg: Message) -> impl Future<Output = MatrixResult<SendReply>> {
         MatrixRequest::new_with_body_ser(
             Method::PUT,
             format!("/rooms/{}/send/m.room.message/{}",
@@ -79,30 +91,77 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
             msg
         ).send(self.cli)
     }
+    /// Sends a message to this room, remembering it in `store` as a
+    /// `PendingTransaction` until we know whether it got there.
+    ///
+    /// See `PendingTransaction::send_stored` for details.
+    pub fn send_stored(&self, store: Arc<dyn StateStore>, msg: Message) -> impl Future<Output = MatrixResult<SendReply>> {
+        let txn = ::serde_json::to_value(msg)
+            .map(|content| PendingTransaction {
+                room: Room::from_id(self.room.id.to_string()),
+                txn_id: self.cli.get_txnid().to_string(),
+                event_type: "m.room.message".into(),
+                content
+            }.send_stored(self.cli, store));
+        async move {
+            txn?.await
+        }
+    }
     /// Wrapper function that sends a `Message::Notice` with the specified unformatted text
     /// to this room. Provided for convenience purposes.
-    pub fn send_simple<T: Into<String>>(&mut self, msg: T) -> impl Future<Item = SendReply, Error = MatrixError> {
+    pub fn send_simple<T: Into<String>>(&self, msg: T) -> impl Future<Output = MatrixResult<SendReply>> {
         let msg = Message::Notice {
             body: msg.into(),
             formatted_body: None,
-            format: None
+            format: None,
+            relates_to: None,
+            new_content: None
         };
         self.send(msg)
     }
     /// Wrapper function that sends a `Message::Notice` with the specified HTML-formatted text
     /// (and accompanying unformatted text, if given) to this room.
-    pub fn send_html<T, U>(&mut self, msg: T, unformatted: U) -> impl Future<Item = SendReply, Error = MatrixError>
+    pub fn send_html<T, U>(&self, msg: T, unformatted: U) -> impl Future<Output = MatrixResult<SendReply>>
         where T: Into<String>, U: Into<Option<String>> {
         let m = msg.into();
         let msg = Message::Notice {
             body: unformatted.into().unwrap_or(m.clone()),
             formatted_body: Some(m),
-            format: Some("org.matrix.custom.html".into())
+            format: Some("org.matrix.custom.html".into()),
+            relates_to: None,
+            new_content: None
         };
         self.send(msg)
     }
+    /// Sends a message to this room as a reply to the event `to`.
+    ///
+    /// If `to` is a message, the message sent quotes it, for the benefit of
+    /// clients that don't understand replies (see `Message::make_reply`).
+    /// Events without an ID (which only turn up in the stripped state of
+    /// rooms we haven't joined) can't be replied to, so `msg` is just sent
+    /// as-is.
+    pub fn reply(&self, to: &Event, mut msg: Message) -> impl Future<Output = MatrixResult<SendReply>> {
+        if let Some(ref rd) = to.room_data {
+            if let Content::RoomMessage(ref orig) = to.content {
+                msg.make_reply(&self.room.id, &rd.event_id, &rd.sender, orig);
+            }
+            else {
+                let mut rel = msg.relates_to().cloned().unwrap_or_default();
+                rel.in_reply_to = Some(InReplyTo { event_id: rd.event_id.clone() });
+                msg.set_relates_to(Some(rel));
+            }
+        }
+        self.send(msg)
+    }
+    /// Edits the message with ID `event_id` (which must have been sent by this
+    /// user), changing its content to `msg`.
+    ///
+    /// See `Message::edit_of` for what gets sent.
+    pub fn edit(&self, event_id: &str, msg: Message) -> impl Future<Output = MatrixResult<SendReply>> {
+        self.send(Message::edit_of(event_id, msg))
+    }
     /// Send a read receipt for a given event ID.
-    pub fn read_receipt(&mut self, eventid: &str) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn read_receipt(&self, eventid: &str) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_basic(Method::POST, format!("/rooms/{}/receipt/m.read/{}", self.room.id, eventid))
             .discarding_send(self.cli)
     }
@@ -116,30 +175,26 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// state events, such as `m.room.name`, can be found in the `content`
     /// module (`content::room::Name` for `m.room.name`).
     ///
-    /// If the event was not found, an error will be thrown of type
-    /// `HttpCode(http::StatusCode::NotFound)`.
-    pub fn get_typed_state<T: DeserializeOwned + 'static>(&mut self, ev_type: &str, key: Option<&str>) -> impl Future<Item = T, Error = MatrixError> {
+    /// If the event was not found, an error will be returned for which
+    /// `MatrixError::is_not_found()` is true.
+    pub fn get_typed_state<T: DeserializeOwned + 'static>(&self, ev_type: &str, key: Option<&str>) -> impl Future<Output = MatrixResult<T>> {
         MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/state/{}/{}",
                                               self.room.id,
                                               ev_type,
                                               key.unwrap_or("")))
             .send(self.cli)
     }
-    pub fn get_typed_state_opt<T: DeserializeOwned + 'static>(&mut self, ev_type: &str, key: Option<&str>) -> impl Future<Item = Option<T>, Error = MatrixError> {
-       self.get_typed_state(ev_type, key)
-          .then(move |res| {
-             match res {
-                Ok(res) => Ok(Some(res)),
-                Err(e) => {
-                   if let MatrixError::BadRequest(ref brk) = e {
-                      if brk.errcode == "M_NOT_FOUND" {
-                         return Ok(None)
-                      }
-                   }
-                   Err(e)
-                }
-             }
-          })
+    /// Like `get_typed_state`, but returns `None` instead of an error if the
+    /// state event was not found.
+    pub fn get_typed_state_opt<T: DeserializeOwned + 'static>(&self, ev_type: &str, key: Option<&str>) -> impl Future<Output = MatrixResult<Option<T>>> {
+       let fut = self.get_typed_state(ev_type, key);
+       async move {
+          match fut.await {
+             Ok(res) => Ok(Some(res)),
+             Err(ref e) if e.is_not_found() => Ok(None),
+             Err(e) => Err(e)
+          }
+       }
     }
     /// State events can be sent using this endpoint. These events will be
     /// overwritten if the <event type> (`ev_type`) and <state key> (`key`) all
@@ -148,7 +203,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// Like `get_state`, the value here can be any object that implements
     /// `Serialize`, allowing you to use the state API to store arbitrary
     /// objects. See the `get_state` docs for more.
-    pub fn set_typed_state<T: Serialize>(&mut self, ev_type: &str, key: Option<&str>, val: T) -> impl Future<Item = SetStateReply, Error = MatrixError> {
+    pub fn set_typed_state<T: Serialize>(&self, ev_type: &str, key: Option<&str>, val: T) -> impl Future<Output = MatrixResult<SetStateReply>> {
         MatrixRequest::new_with_body_ser(
             Method::PUT,
             format!("/rooms/{}/state/{}/{}",
@@ -158,7 +213,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
         ).send(self.cli)
     }
     /// Get the state events for the current state of a room.
-    pub fn get_all_state(&mut self) -> impl Future<Item = Vec<Event>, Error = MatrixError> {
+    pub fn get_all_state(&self) -> impl Future<Output = MatrixResult<Vec<Event>>> {
         MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/state", self.room.id))
             .send(self.cli)
     }
@@ -166,12 +221,12 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// You must have permission to retrieve this event (e.g. by being a member of the room for
     /// this event)
-    pub fn get_event(&mut self, id: &str) -> impl Future<Item = Event, Error = MatrixError> {
+    pub fn get_event(&self, id: &str) -> impl Future<Output = MatrixResult<Event>> {
         MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/event/{}", self.room.id, id))
             .send(self.cli)
     }
     /// Get the list of member events for this room.
-    pub fn get_members(&mut self) -> impl Future<Item = ChunkReply, Error = MatrixError> {
+    pub fn get_members(&self) -> impl Future<Output = MatrixResult<ChunkReply>> {
         MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/members", self.room.id))
             .send(self.cli)
     }
@@ -183,7 +238,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// This API is primarily for Application Services and should be faster to
     /// respond than `get_members()`, as it can be implemented more efficiently
     /// on the server.
-    pub fn get_joined_members(&mut self) -> impl Future<Item = JoinedMembersReply, Error = MatrixError> {
+    pub fn get_joined_members(&self) -> impl Future<Output = MatrixResult<JoinedMembersReply>> {
         MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/joined_members", self.room.id))
             .send(self.cli)
     }
@@ -202,7 +257,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///   this endpoint.
     /// - `backward`: Whether to paginate backward, or forward; true = back-pagination.
     /// - `limit`: The maximum number of events to return. (default: 10)
-    pub fn get_messages(&mut self, from: &str, to: Option<&str>, backward: bool, limit: Option<u32>) -> impl Future<Item = MessagesReply, Error = MatrixError> {
+    pub fn get_messages(&self, from: &str, to: Option<&str>, backward: bool, limit: Option<u32>) -> impl Future<Output = MatrixResult<MessagesReply>> {
         let mut req = MatrixRequest::new_basic(Method::GET, format!("/rooms/{}/messages", self.room.id));
         req.params.insert("from".into(), from.into());
         if let Some(to) = to {
@@ -223,7 +278,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// Users may redact their own events, and any user with a power level
     /// greater than or equal to the redact power level of the room may redact
     /// events there.
-    pub fn redact(&mut self, eventid: &str, reason: Option<&str>) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn redact(&self, eventid: &str, reason: Option<&str>) -> impl Future<Output = MatrixResult<()>> {
         let mut body = vec![];
         body.extend(reason.map(|x| ("reason", x.to_string())));
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/redact/{}/{}",
@@ -235,7 +290,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// milliseconds where N is the value specified in the timeout key.
     /// Alternatively, if typing is false, it tells the server that the user has
     /// stopped typing.
-    pub fn typing(&mut self, typing: bool, timeout: Option<usize>) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn typing(&self, typing: bool, timeout: Option<usize>) -> impl Future<Output = MatrixResult<()>> {
         let mut body = vec![("typing", typing.to_string())];
         body.extend(timeout.map(|x| ("timeout", x.to_string())));
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/typing/{}",
@@ -251,7 +306,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// After a user has joined a room, the room will appear as an entry in the
     /// values in the `SyncStream`.
-    pub fn join(&mut self) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn join(&self) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_basic(Method::POST, format!("/rooms/{}/join", self.room.id))
             .discarding_send(self.cli)
     }
@@ -266,7 +321,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// The user will still be allowed to retrieve history from the room which
     /// they were previously allowed to see.
-    pub fn leave(&mut self) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn leave(&self) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_basic(Method::POST, format!("/rooms/{}/leave", self.room.id))
             .discarding_send(self.cli)
     }
@@ -279,7 +334,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// If the user is currently joined to the room, they will implicitly leave
     /// the room as part of this API call.
-    pub fn forget(&mut self) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn forget(&self) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_basic(Method::POST, format!("/rooms/{}/forget", self.room.id))
             .discarding_send(self.cli)
     }
@@ -287,7 +342,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// The caller must have the required power level in order to perform this
     /// operation.
-    pub fn kick_user(&mut self, user_id: &str, reason: Option<&str>) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn kick_user(&self, user_id: &str, reason: Option<&str>) -> impl Future<Output = MatrixResult<()>> {
         let mut body = vec![("user_id", user_id.to_string())];
         body.extend(reason.map(|x| ("reason", x.to_string())));
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/kick", self.room.id),
@@ -300,7 +355,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// it until they are unbanned.
     ///
     /// The caller must have the required power level in order to perform this operation.
-    pub fn ban_user(&mut self, user_id: &str, reason: Option<&str>) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn ban_user(&self, user_id: &str, reason: Option<&str>) -> impl Future<Output = MatrixResult<()>> {
         let mut body = vec![("user_id", user_id.to_string())];
         body.extend(reason.map(|x| ("reason", x.to_string())));
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/ban", self.room.id),
@@ -313,7 +368,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// The caller must have the required power level in order to perform this
     /// operation.
-    pub fn unban_user(&mut self, user_id: &str) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn unban_user(&self, user_id: &str) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/unban", self.room.id),
                                      vec![("user_id", user_id.to_string())])
             .discarding_send(self.cli)
@@ -332,7 +387,7 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     /// Matrix identifier of the invitee. The other is documented in the third
     /// party invites section of the Matrix spec, and is not implemented in
     /// *Glitch in the Matrix* (yet!)
-    pub fn invite_user(&mut self, user_id: &str) -> impl Future<Item = (), Error = MatrixError> {
+    pub fn invite_user(&self, user_id: &str) -> impl Future<Output = MatrixResult<()>> {
         MatrixRequest::new_with_body(Method::POST, format!("/rooms/{}/invite", self.room.id),
                                      vec![("user_id", user_id.to_string())])
             .discarding_send(self.cli)
@@ -342,22 +397,21 @@ impl<'a, 'b, 'c, R> RoomClient<'a, 'b, 'c, R> where R: MatrixRequestable {
     ///
     /// The `user_id` is a `String` here, not a `&str`, because it is stored in
     /// a future that outlives this function.
-    pub fn get_user_power_level(&mut self, user_id: String) -> impl Future<Item = u32, Error = MatrixError> {
+    pub fn get_user_power_level(&self, user_id: String) -> impl Future<Output = MatrixResult<u32>> {
         let fut = self.get_typed_state::<PowerLevels>("m.room.power_levels", None);
-        Box::new(fut.map(move |x| {
-            if let Some(pl) = x.users.get(&user_id) {
-                *pl
+        async move {
+            match fut.await {
+                Ok(x) => {
+                    if let Some(pl) = x.users.get(&user_id) {
+                        Ok(*pl)
+                    }
+                    else {
+                        Ok(x.users_default)
+                    }
+                },
+                Err(ref e) if e.is_not_found() => Ok(0),
+                Err(e) => Err(e)
             }
-            else {
-                x.users_default
-            }
-        }).or_else(|e| {
-            if let MatrixError::BadRequest(ref brk) = e {
-                if brk.errcode == "M_NOT_FOUND" {
-                    return Ok(0)
-                }
-            }
-            Err(e)
-        }))
+        }
     }
 }
@@ -0,0 +1,28 @@
+//! Saving and restoring logged-in sessions.
+
+/// Everything needed to carry on using a logged-in session, without having to
+/// log in again.
+///
+/// This can be serialized and stored somewhere, then used to make a new client
+/// with `MatrixClient::restore_session` (or `MatrixClientBuilder::restore_session`).
+///
+/// **NB:** this contains the access token, so treat it like a password.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// The URL of the homeserver.
+    pub homeserver_url: String,
+    /// The fully-qualified user ID of the logged-in user.
+    pub user_id: String,
+    /// The ID of the device the access token belongs to, if known.
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// The access token.
+    pub access_token: String,
+    /// The `next_batch` token from the last `/sync` reply that was processed,
+    /// if any.
+    ///
+    /// Sessions exported from a `SyncStream` fill this in; sessions exported
+    /// from a `MatrixClient` don't.
+    #[serde(default)]
+    pub sync_token: Option<String>
+}
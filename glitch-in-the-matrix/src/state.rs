@@ -0,0 +1,357 @@
+//! Keeping track of the state of rooms on the client side, so you don't have
+//! to ask the homeserver every time you want to know a room's name.
+//!
+//! A `RoomState` holds the current state events of one room, and a
+//! `RoomStates` holds one for every room we know about. Give a `RoomStates` to
+//! a `SyncStream` (with `set_room_states`) to keep it up to date with every
+//! `/sync` reply.
+//!
+//! `RoomState` can also work out what to call a room, and its members, the way
+//! the spec says clients should.
+
+use types::content::Content;
+use types::content::room::{Member, PowerLevels};
+use types::content::room::types::{JoinRule, Membership};
+use types::events::Event;
+use types::room::Room;
+use types::sync::{EventSource, RoomSummary, SyncReply};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+
+/// The current state of a room: the latest state event for each
+/// (event type, state key) pair.
+#[derive(Clone, Debug, Default)]
+pub struct RoomState {
+    events: HashMap<(String, String), Event>,
+    summary: RoomSummary
+}
+impl RoomState {
+    /// Make a new, empty `RoomState`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Apply an event to the state. Events that aren't state events are
+    /// ignored.
+    ///
+    /// Events must be applied in the order they happened, as later ones
+    /// replace earlier ones with the same type and state key.
+    pub fn apply(&mut self, event: &Event) {
+        if let Some(ref sd) = event.state_data {
+            self.events.insert((event.event_type.clone(), sd.state_key.clone()), event.clone());
+        }
+    }
+    /// Update the room's summary with the fields present in a newer one (from
+    /// `JoinedRoom::summary`); fields that are missing haven't changed.
+    pub fn apply_summary(&mut self, summary: &RoomSummary) {
+        merge_summary(&mut self.summary, summary);
+    }
+    /// Apply a `StateUpdate`, with `apply` or `apply_summary`.
+    pub(crate) fn apply_update(&mut self, update: StateUpdate<'_>) {
+        match update {
+            StateUpdate::Event(event) => self.apply(event),
+            StateUpdate::Summary(summary) => self.apply_summary(summary)
+        }
+    }
+    /// The room's summary, as far as we know it.
+    pub fn summary(&self) -> &RoomSummary {
+        &self.summary
+    }
+    /// Get the state event with the given type and state key, if there is one.
+    pub fn get(&self, event_type: &str, state_key: &str) -> Option<&Event> {
+        // Tuples of `String`s can't be borrowed as tuples of `&str`s, sadly.
+        self.events.get(&(event_type.to_string(), state_key.to_string()))
+    }
+    /// Get the content of the state event with the given type and state key,
+    /// if there is one.
+    pub fn get_content(&self, event_type: &str, state_key: &str) -> Option<&Content> {
+        self.get(event_type, state_key).map(|e| &e.content)
+    }
+    /// Iterate over all of the state events.
+    pub fn events(&self) -> impl Iterator<Item = &Event> {
+        self.events.values()
+    }
+    /// The number of state events.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+    /// Whether there aren't any state events.
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+    /// The room's name (from `m.room.name`), if it has a non-empty one.
+    pub fn name(&self) -> Option<&str> {
+        match self.get_content("m.room.name", "") {
+            Some(Content::RoomName(n)) if !n.name.is_empty() => Some(&n.name),
+            _ => None
+        }
+    }
+    /// The room's topic (from `m.room.topic`), if it has one.
+    pub fn topic(&self) -> Option<&str> {
+        match self.get_content("m.room.topic", "") {
+            Some(Content::RoomTopic(t)) => Some(&t.topic),
+            _ => None
+        }
+    }
+    /// The room's canonical alias (from `m.room.canonical_alias`), if it has
+    /// a non-empty one.
+    pub fn canonical_alias(&self) -> Option<&str> {
+        match self.get_content("m.room.canonical_alias", "") {
+            Some(Content::RoomCanonicalAlias(a)) if !a.alias.is_empty() => Some(&a.alias),
+            _ => None
+        }
+    }
+    /// The room's join rule (from `m.room.join_rules`), if we know it.
+    pub fn join_rule(&self) -> Option<&JoinRule> {
+        match self.get_content("m.room.join_rules", "") {
+            Some(Content::RoomJoinRules(j)) => Some(&j.join_rule),
+            _ => None
+        }
+    }
+    /// The room's power levels (from `m.room.power_levels`), if we know them.
+    pub fn power_levels(&self) -> Option<&PowerLevels> {
+        match self.get_content("m.room.power_levels", "") {
+            Some(Content::RoomPowerLevels(p)) => Some(p),
+            _ => None
+        }
+    }
+    /// The power level of a user, according to the room's power levels.
+    ///
+    /// If the room has no `m.room.power_levels` event, this is 100 for the
+    /// room's creator and 0 for everyone else, as the spec says.
+    pub fn user_power_level(&self, user_id: &str) -> u32 {
+        if let Some(pl) = self.power_levels() {
+            return pl.users.get(user_id).cloned().unwrap_or(pl.users_default);
+        }
+        match self.get_content("m.room.create", "") {
+            Some(Content::RoomCreate(c)) if c.creator == user_id => 100,
+            _ => 0
+        }
+    }
+    /// The membership event content for a user, if we have it.
+    pub fn member(&self, user_id: &str) -> Option<&Member> {
+        match self.get_content("m.room.member", user_id) {
+            Some(Content::RoomMember(m)) => Some(m),
+            _ => None
+        }
+    }
+    /// Iterate over the users we have membership events for, whatever their
+    /// membership is, along with that event's content.
+    pub fn members(&self) -> impl Iterator<Item = (&str, &Member)> {
+        self.events.iter()
+            .filter(|(k, _)| k.0 == "m.room.member")
+            .filter_map(|(k, e)| match e.content {
+                Content::RoomMember(ref m) => Some((&k.1 as &str, m)),
+                _ => None
+            })
+    }
+    /// Iterate over the users whose membership is `join`.
+    pub fn joined_members(&self) -> impl Iterator<Item = (&str, &Member)> {
+        self.members().filter(|(_, m)| m.membership == Membership::Join)
+    }
+    /// Work out what to call a member of the room.
+    ///
+    /// This is their display name, unless they don't have one (in which case
+    /// it's their user ID), or another joined or invited member has the same
+    /// one (in which case their user ID is added after it in brackets, e.g.
+    /// `Alice (@alice:example.org)`).
+    pub fn member_display_name(&self, user_id: &str) -> String {
+        let name = match self.member(user_id).and_then(|m| m.displayname.as_ref()) {
+            Some(n) if !n.is_empty() => n,
+            _ => return user_id.to_string()
+        };
+        let ambiguous = self.members()
+            .filter(|&(id, m)| id != user_id && is_current(m))
+            .any(|(_, m)| m.displayname.as_ref() == Some(name));
+        if ambiguous {
+            format!("{} ({})", name, user_id)
+        }
+        else {
+            name.clone()
+        }
+    }
+    /// Work out what to call the room, as seen by the user `own_user_id`.
+    ///
+    /// This is, in order of preference:
+    ///
+    /// - the room's name, if it has one;
+    /// - its canonical alias, if it has one;
+    /// - the names of some of its other members (its "heroes"), e.g. `Alice,
+    ///   Bob and 3 others`;
+    /// - `Empty room`, or something like `Empty room (was Alice)` if the other
+    ///   members have all left.
+    ///
+    /// The heroes and member counts come from the room's summary where we have
+    /// it, and are worked out from the members we know about otherwise.
+    pub fn display_name(&self, own_user_id: &str) -> String {
+        if let Some(name) = self.name() {
+            return name.into();
+        }
+        if let Some(alias) = self.canonical_alias() {
+            return alias.into();
+        }
+        let heroes = match self.summary.heroes {
+            Some(ref h) => h.clone(),
+            None => self.heroes(own_user_id)
+        };
+        let names = heroes.iter()
+            .map(|h| self.member_display_name(h))
+            .collect::<Vec<_>>();
+        let joined = self.summary.joined_member_count
+            .unwrap_or_else(|| self.joined_members().count() as u64);
+        let invited = self.summary.invited_member_count
+            .unwrap_or_else(|| self.members().filter(|(_, m)| m.membership == Membership::Invite).count() as u64);
+        let total = joined + invited;
+        if total <= 1 || names.is_empty() {
+            if names.is_empty() {
+                return "Empty room".into();
+            }
+            return format!("Empty room (was {})", list_names(&names));
+        }
+        let others = (total - 1).saturating_sub(names.len() as u64);
+        match others {
+            0 => list_names(&names),
+            1 => format!("{} and 1 other", names.join(", ")),
+            n => format!("{} and {} others", names.join(", "), n)
+        }
+    }
+    /// Work out who to name the room after, if the server didn't tell us: up to
+    /// five other users who are joined or invited (or, failing that, who have
+    /// left), ordered by user ID.
+    fn heroes(&self, own_user_id: &str) -> Vec<String> {
+        let mut current = vec![];
+        let mut former = vec![];
+        for (id, m) in self.members() {
+            if id == own_user_id {
+                continue;
+            }
+            if is_current(m) {
+                current.push(id.to_string());
+            }
+            else {
+                former.push(id.to_string());
+            }
+        }
+        let mut heroes = if current.is_empty() { former } else { current };
+        heroes.sort();
+        heroes.truncate(5);
+        heroes
+    }
+}
+/// Whether a member is joined to or invited to the room.
+fn is_current(m: &Member) -> bool {
+    m.membership == Membership::Join || m.membership == Membership::Invite
+}
+/// Join names together like `Alice, Bob and Charlie`.
+fn list_names(names: &[String]) -> String {
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        _ => names.join("")
+    }
+}
+/// The `RoomState` of every room we know about.
+///
+/// This is a cheaply cloneable handle: clones share the same states, so one
+/// can be given to a `SyncStream` to keep up to date, and another queried
+/// from elsewhere (e.g. in event handlers).
+#[derive(Clone, Debug, Default)]
+pub struct RoomStates {
+    rooms: Arc<RwLock<HashMap<Room<'static>, RoomState>>>
+}
+impl RoomStates {
+    /// Make a new `RoomStates` that doesn't know about any rooms.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Apply all of the state events in a `/sync` reply: the `state` and
+    /// `timeline` of joined and left rooms, and the stripped state of rooms
+    /// the user has been invited to or knocked on. The summaries of joined
+    /// rooms are applied too.
+    pub fn apply_sync(&self, sync: &SyncReply) {
+        apply_sync_to_map(&mut self.rooms.write().unwrap(), sync);
+    }
+    /// Get a copy of a room's state, if we know about the room.
+    ///
+    /// Use `with_room` to avoid copying the whole state.
+    pub fn get(&self, room: &Room<'_>) -> Option<RoomState> {
+        self.with_room(room, |s| s.clone())
+    }
+    /// Call `func` with a room's state, if we know about the room, and return
+    /// what it returns.
+    ///
+    /// The states can't be updated while `func` runs, so don't take too long.
+    pub fn with_room<F, T>(&self, room: &Room<'_>, func: F) -> Option<T> where F: FnOnce(&RoomState) -> T {
+        let rooms = self.rooms.read().unwrap();
+        rooms.get(&Room::from_id(room.id.to_string())).map(func)
+    }
+    /// Get the rooms we know about.
+    pub fn rooms(&self) -> Vec<Room<'static>> {
+        self.rooms.read().unwrap().keys().cloned().collect()
+    }
+    /// Set a room's state, replacing whatever we had before.
+    pub fn insert(&self, room: Room<'static>, state: RoomState) {
+        self.rooms.write().unwrap().insert(room, state);
+    }
+    /// Forget about a room, returning its state if we knew about it.
+    pub fn remove(&self, room: &Room<'_>) -> Option<RoomState> {
+        self.rooms.write().unwrap().remove(&Room::from_id(room.id.to_string()))
+    }
+}
+/// Update `old` with the fields present in `new`, which is a newer summary of
+/// the same room.
+pub(crate) fn merge_summary(old: &mut RoomSummary, new: &RoomSummary) {
+    if new.heroes.is_some() {
+        old.heroes = new.heroes.clone();
+    }
+    if new.joined_member_count.is_some() {
+        old.joined_member_count = new.joined_member_count;
+    }
+    if new.invited_member_count.is_some() {
+        old.invited_member_count = new.invited_member_count;
+    }
+}
+/// Something in a `/sync` reply that changes a room's state.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum StateUpdate<'a> {
+    /// A state event, to be applied as with `RoomState::apply`.
+    Event(&'a Event),
+    /// A joined room's summary, to be merged as with `RoomState::apply_summary`.
+    Summary(&'a RoomSummary)
+}
+/// Pass everything in a `/sync` reply that changes room state to `sink`, along
+/// with the room it's for, in the order it should be applied: the state events
+/// described in `RoomStates::apply_sync`, followed by the summaries of joined
+/// rooms.
+///
+/// This is how `RoomStates` and the `StateStore`s save a reply, so they all
+/// agree on what's in it. If `sink` returns an error, this stops and returns it.
+pub(crate) fn for_each_state_update<'a, F, E>(sync: &'a SyncReply, mut sink: F) -> Result<(), E>
+    where F: FnMut(&'a Room<'static>, StateUpdate<'a>) -> Result<(), E> {
+    for evt in sync.events() {
+        match (evt.source, evt.room) {
+            (EventSource::State, Some(room)) | (EventSource::Timeline, Some(room)) |
+                (EventSource::InviteState, Some(room)) | (EventSource::KnockState, Some(room)) => {
+                sink(room, StateUpdate::Event(evt.event))?;
+            },
+            _ => {}
+        }
+    }
+    for (room, jr) in sync.rooms.join.iter() {
+        sink(room, StateUpdate::Summary(&jr.summary))?;
+    }
+    Ok(())
+}
+/// Apply a `/sync` reply to a map of room states, as `RoomStates::apply_sync`
+/// does.
+pub(crate) fn apply_sync_to_map(rooms: &mut HashMap<Room<'static>, RoomState>, sync: &SyncReply) {
+    let res = for_each_state_update(sync, |room, update| {
+        rooms.entry(room.clone())
+            .or_default()
+            .apply_update(update);
+        Ok::<_, Infallible>(())
+    });
+    match res {
+        Ok(()) => {},
+        Err(e) => match e {}
+    }
+}
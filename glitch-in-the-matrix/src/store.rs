@@ -0,0 +1,222 @@
+//! Persisting what we know between runs: the sync token, room state, account
+//! data, and messages that haven't been sent yet.
+//!
+//! A `StateStore` is given to a `SyncStream` (with `set_state_store`), which
+//! saves every `/sync` reply to it in one go before returning the reply. This
+//! crate comes with a `MemoryStore`, which doesn't actually persist anything
+//! but is handy for tests, and a `SqliteStore` (with the `sqlite` feature).
+//!
+//! Messages sent with `RoomClient::send_stored` are remembered in the store as
+//! `PendingTransaction`s until we know whether they got there, so after a
+//! restart you can send them again with `PendingTransaction::send_stored`.
+
+use types::content::room::Member;
+use types::events::Event;
+use types::replies::SendReply;
+use types::room::Room;
+use types::sync::{EventSource, SyncReply};
+use crate::errors::*;
+use crate::request::{MatrixRequest, MatrixRequestable};
+use crate::state::{self, RoomState};
+use http::Method;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+/// An event we're sending (or want to send) to a room, along with the
+/// transaction ID it's being sent with.
+///
+/// The homeserver ignores repeats of a transaction ID from the same access
+/// token, so a `PendingTransaction` can safely be sent again if we don't know
+/// whether it got there (e.g. because we restarted). `StateStore`s forget
+/// about them once they turn up in a `/sync` reply.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingTransaction {
+    /// The room to send the event to.
+    pub room: Room<'static>,
+    /// The transaction ID.
+    pub txn_id: String,
+    /// The event's type (e.g. `m.room.message`).
+    pub event_type: String,
+    /// The event's content.
+    pub content: Value
+}
+impl PendingTransaction {
+    /// Make a new `PendingTransaction`, with a fresh transaction ID.
+    pub fn new<T: Serialize>(room: Room<'static>, event_type: &str, content: T) -> MatrixResult<Self> {
+        Ok(Self {
+            room,
+            txn_id: Uuid::new_v4().to_string(),
+            event_type: event_type.into(),
+            content: ::serde_json::to_value(content)?
+        })
+    }
+    /// Send the event.
+    pub fn send<R: MatrixRequestable>(&self, rq: &R) -> impl Future<Output = MatrixResult<SendReply>> {
+        MatrixRequest::new_with_body_ser(
+            Method::PUT,
+            format!("/rooms/{}/send/{}/{}", self.room.id, self.event_type, self.txn_id),
+            self.content.clone()
+        ).send(rq)
+    }
+    /// Send the event, remembering it in `store` until we know whether it got
+    /// there.
+    ///
+    /// The transaction is added to the store before it's sent. It's removed
+    /// again once the homeserver replies, unless it failed with a transient
+    /// error (see `MatrixError::is_transient`), in which case it's left for you
+    /// to send again later. (If the reply never makes it to us, the next
+    /// `/sync` containing the event removes it instead.)
+    pub fn send_stored<R: MatrixRequestable>(self, rq: &R, store: Arc<dyn StateStore>) -> impl Future<Output = MatrixResult<SendReply>> {
+        let added = store.add_pending_transaction(&self);
+        let fut = self.send(rq);
+        async move {
+            added?;
+            let res = fut.await;
+            match res {
+                Err(ref e) if e.is_transient() => {},
+                _ => store.remove_pending_transaction(&self.txn_id)?
+            }
+            res
+        }
+    }
+}
+/// Somewhere to store the state of the client, so that it can carry on where
+/// it left off after a restart.
+///
+/// `save_sync` must store everything in a reply at once: if it fails, or the
+/// program is killed halfway through, the store must be left as if it had
+/// never been called. Stores are shared between the `SyncStream` updating them
+/// and whatever else wants to query them, so all the methods take `&self`.
+pub trait StateStore: Send + Sync {
+    /// Get the `next_batch` token of the last `/sync` reply saved, if any.
+    fn sync_token(&self) -> MatrixResult<Option<String>>;
+    /// Save a `/sync` reply: its `next_batch` token, the state events and
+    /// summaries of every room in it (as `RoomStates::apply_sync` would), its
+    /// global and per-room account data, and the fact that any
+    /// `PendingTransaction`s whose events it contains have been sent.
+    fn save_sync(&self, sync: &SyncReply) -> MatrixResult<()>;
+    /// Get the rooms we have state for.
+    fn rooms(&self) -> MatrixResult<Vec<Room<'static>>>;
+    /// Get a room's state, if we have any.
+    fn room_state(&self, room: &Room<'_>) -> MatrixResult<Option<RoomState>>;
+    /// Get the membership event content for a user in a room, if we have it.
+    fn member(&self, room: &Room<'_>, user_id: &str) -> MatrixResult<Option<Member>> {
+        Ok(self.room_state(room)?
+           .and_then(|s| s.member(user_id).cloned()))
+    }
+    /// Get the membership event content for every user we know about in a
+    /// room, whatever their membership is.
+    fn members(&self, room: &Room<'_>) -> MatrixResult<Vec<(String, Member)>> {
+        Ok(self.room_state(room)?
+           .map(|s| s.members().map(|(id, m)| (id.to_string(), m.clone())).collect())
+           .unwrap_or_default())
+    }
+    /// Get the latest global account data event of the given type.
+    fn account_data(&self, event_type: &str) -> MatrixResult<Option<Event>>;
+    /// Get the latest account data event of the given type for a room.
+    fn room_account_data(&self, room: &Room<'_>, event_type: &str) -> MatrixResult<Option<Event>>;
+    /// Remember a transaction that's about to be sent, so it can be sent again
+    /// if we don't find out whether it was.
+    ///
+    /// Adding a transaction with the same ID as one that's already pending
+    /// replaces it, keeping its place in `pending_transactions`.
+    fn add_pending_transaction(&self, txn: &PendingTransaction) -> MatrixResult<()>;
+    /// Forget about a pending transaction.
+    fn remove_pending_transaction(&self, txn_id: &str) -> MatrixResult<()>;
+    /// Get the transactions that haven't turned up in a `/sync` reply yet, in
+    /// the order they were added.
+    fn pending_transactions(&self) -> MatrixResult<Vec<PendingTransaction>>;
+}
+/// Get the account data in a `/sync` reply, keyed by room (`None` for global
+/// account data) and event type.
+fn account_data_events(sync: &SyncReply) -> impl Iterator<Item = (Option<&Room<'static>>, &Event)> {
+    sync.events().filter_map(|evt| match evt.source {
+        EventSource::AccountData => Some((None, evt.event)),
+        EventSource::RoomAccountData => Some((evt.room, evt.event)),
+        _ => None
+    })
+}
+/// Get the transaction IDs of the events we sent that are in a `/sync` reply.
+fn sent_txn_ids(sync: &SyncReply) -> impl Iterator<Item = &str> {
+    sync.events()
+        .filter(|evt| evt.source == EventSource::Timeline)
+        .filter_map(|evt| evt.event.room_data.as_ref()?.unsigned.as_ref()?.transaction_id.as_ref())
+        .map(|x| x as &str)
+}
+#[derive(Default)]
+struct MemoryStoreData {
+    sync_token: Option<String>,
+    rooms: HashMap<Room<'static>, RoomState>,
+    account_data: HashMap<(Option<Room<'static>>, String), Event>,
+    pending: Vec<PendingTransaction>
+}
+/// A `StateStore` that keeps everything in memory, and so forgets it all
+/// when dropped.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryStoreData>
+}
+impl MemoryStore {
+    /// Make a new, empty `MemoryStore`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl StateStore for MemoryStore {
+    fn sync_token(&self) -> MatrixResult<Option<String>> {
+        Ok(self.data.lock().unwrap().sync_token.clone())
+    }
+    fn save_sync(&self, sync: &SyncReply) -> MatrixResult<()> {
+        let mut data = self.data.lock().unwrap();
+        state::apply_sync_to_map(&mut data.rooms, sync);
+        for (room, event) in account_data_events(sync) {
+            data.account_data.insert((room.cloned(), event.event_type.clone()), event.clone());
+        }
+        for txn_id in sent_txn_ids(sync) {
+            data.pending.retain(|t| t.txn_id != txn_id);
+        }
+        data.sync_token = Some(sync.next_batch.clone());
+        Ok(())
+    }
+    fn rooms(&self) -> MatrixResult<Vec<Room<'static>>> {
+        Ok(self.data.lock().unwrap().rooms.keys().cloned().collect())
+    }
+    fn room_state(&self, room: &Room<'_>) -> MatrixResult<Option<RoomState>> {
+        Ok(self.data.lock().unwrap().rooms.get(&Room::from_id(room.id.to_string())).cloned())
+    }
+    fn member(&self, room: &Room<'_>, user_id: &str) -> MatrixResult<Option<Member>> {
+        Ok(self.data.lock().unwrap().rooms.get(&Room::from_id(room.id.to_string()))
+           .and_then(|s| s.member(user_id).cloned()))
+    }
+    fn account_data(&self, event_type: &str) -> MatrixResult<Option<Event>> {
+        Ok(self.data.lock().unwrap().account_data.get(&(None, event_type.to_string())).cloned())
+    }
+    fn room_account_data(&self, room: &Room<'_>, event_type: &str) -> MatrixResult<Option<Event>> {
+        let key = (Some(Room::from_id(room.id.to_string())), event_type.to_string());
+        Ok(self.data.lock().unwrap().account_data.get(&key).cloned())
+    }
+    fn add_pending_transaction(&self, txn: &PendingTransaction) -> MatrixResult<()> {
+        let mut data = self.data.lock().unwrap();
+        match data.pending.iter_mut().find(|t| t.txn_id == txn.txn_id) {
+            Some(t) => *t = txn.clone(),
+            None => data.pending.push(txn.clone())
+        }
+        Ok(())
+    }
+    fn remove_pending_transaction(&self, txn_id: &str) -> MatrixResult<()> {
+        self.data.lock().unwrap().pending.retain(|t| t.txn_id != txn_id);
+        Ok(())
+    }
+    fn pending_transactions(&self) -> MatrixResult<Vec<PendingTransaction>> {
+        Ok(self.data.lock().unwrap().pending.clone())
+    }
+}
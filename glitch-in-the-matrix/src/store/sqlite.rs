@@ -0,0 +1,213 @@
+//! A `StateStore` backed by an SQLite database.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use types::content::Content;
+use types::sync::RoomSummary;
+use crate::state::StateUpdate;
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::Mutex;
+use super::*;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS gm_sync_token (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    token TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS gm_room_state (
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    state_key TEXT NOT NULL,
+    event TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_type, state_key)
+);
+CREATE TABLE IF NOT EXISTS gm_room_summary (
+    room_id TEXT PRIMARY KEY NOT NULL,
+    summary TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS gm_account_data (
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    event TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_type)
+);
+CREATE TABLE IF NOT EXISTS gm_pending_transactions (
+    txn_id TEXT PRIMARY KEY NOT NULL,
+    room_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    content TEXT NOT NULL
+);
+";
+/// Serialize an event the way the homeserver sends it, so it can be
+/// deserialized again.
+///
+/// (`Event`'s `Serialize` impl keeps the room and state fields separate.)
+fn event_to_json(event: &Event) -> MatrixResult<String> {
+    let mut obj = Map::new();
+    obj.insert("type".into(), event.event_type.clone().into());
+    obj.insert("content".into(), ::serde_json::to_value(&event.content)?);
+    for data in &[::serde_json::to_value(&event.room_data)?, ::serde_json::to_value(&event.state_data)?] {
+        if let Value::Object(ref o) = *data {
+            for (k, v) in o {
+                if !v.is_null() {
+                    obj.insert(k.clone(), v.clone());
+                }
+            }
+        }
+    }
+    Ok(::serde_json::to_string(&obj)?)
+}
+/// A `StateStore` that keeps everything in an SQLite database (in tables
+/// prefixed with `gm_`, so it can share one with your own stuff).
+///
+/// Each `/sync` reply is saved in a single transaction.
+pub struct SqliteStore {
+    conn: Mutex<Connection>
+}
+impl SqliteStore {
+    /// Open (or create) a database at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> MatrixResult<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+    /// Make a new database in memory, which is forgotten when dropped.
+    pub fn open_in_memory() -> MatrixResult<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+    /// Use an existing database connection, creating the tables if they don't
+    /// exist yet.
+    pub fn from_connection(conn: Connection) -> MatrixResult<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+    fn get_event(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> MatrixResult<Option<Event>> {
+        let conn = self.conn.lock().unwrap();
+        let json: Option<String> = conn.query_row(sql, params, |row| row.get(0)).optional()?;
+        match json {
+            Some(j) => Ok(Some(::serde_json::from_str(&j)?)),
+            None => Ok(None)
+        }
+    }
+}
+impl StateStore for SqliteStore {
+    fn sync_token(&self) -> MatrixResult<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row("SELECT token FROM gm_sync_token WHERE id = 0", [], |row| row.get(0))
+           .optional()?)
+    }
+    fn save_sync(&self, sync: &SyncReply) -> MatrixResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        // Dropping the transaction without committing it (e.g. if we return
+        // early with an error) rolls it back.
+        let txn = conn.transaction()?;
+        state::for_each_state_update(sync, |room, update| -> MatrixResult<()> {
+            match update {
+                StateUpdate::Event(event) => {
+                    if let Some(ref sd) = event.state_data {
+                        txn.execute("INSERT OR REPLACE INTO gm_room_state (room_id, event_type, state_key, event) VALUES (?1, ?2, ?3, ?4)",
+                                    params![room.id, event.event_type, sd.state_key, event_to_json(event)?])?;
+                    }
+                },
+                StateUpdate::Summary(new) => {
+                    // Summaries only contain the fields that have changed, so
+                    // merge them with what we had already.
+                    let old: Option<String> = txn.query_row("SELECT summary FROM gm_room_summary WHERE room_id = ?1",
+                                                            params![room.id], |row| row.get(0)).optional()?;
+                    let mut summary: RoomSummary = match old {
+                        Some(old) => ::serde_json::from_str(&old)?,
+                        None => RoomSummary::default()
+                    };
+                    state::merge_summary(&mut summary, new);
+                    txn.execute("INSERT OR REPLACE INTO gm_room_summary (room_id, summary) VALUES (?1, ?2)",
+                                params![room.id, ::serde_json::to_string(&summary)?])?;
+                }
+            }
+            Ok(())
+        })?;
+        for (room, event) in account_data_events(sync) {
+            let room_id = room.map(|r| &r.id as &str).unwrap_or("");
+            txn.execute("INSERT OR REPLACE INTO gm_account_data (room_id, event_type, event) VALUES (?1, ?2, ?3)",
+                        params![room_id, event.event_type, event_to_json(event)?])?;
+        }
+        for txn_id in sent_txn_ids(sync) {
+            txn.execute("DELETE FROM gm_pending_transactions WHERE txn_id = ?1", params![txn_id])?;
+        }
+        txn.execute("INSERT OR REPLACE INTO gm_sync_token (id, token) VALUES (0, ?1)", params![sync.next_batch])?;
+        txn.commit()?;
+        Ok(())
+    }
+    fn rooms(&self) -> MatrixResult<Vec<Room<'static>>> {
+        let conn = self.conn.lock().unwrap();
+        // Rooms can have a summary without any state (and vice versa).
+        let mut stmt = conn.prepare("SELECT room_id FROM gm_room_state UNION SELECT room_id FROM gm_room_summary")?;
+        let rooms = stmt.query_map([], |row| row.get::<_, String>(0))?
+            .map(|id| id.map(Room::from_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rooms)
+    }
+    fn room_state(&self, room: &Room<'_>) -> MatrixResult<Option<RoomState>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT event FROM gm_room_state WHERE room_id = ?1")?;
+        let events = stmt.query_map(params![room.id], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        let summary: Option<String> = conn.query_row("SELECT summary FROM gm_room_summary WHERE room_id = ?1",
+                                                     params![room.id], |row| row.get(0)).optional()?;
+        if events.is_empty() && summary.is_none() {
+            return Ok(None);
+        }
+        let mut state = RoomState::new();
+        for json in events {
+            state.apply(&::serde_json::from_str(&json)?);
+        }
+        if let Some(summary) = summary {
+            state.apply_summary(&::serde_json::from_str(&summary)?);
+        }
+        Ok(Some(state))
+    }
+    fn member(&self, room: &Room<'_>, user_id: &str) -> MatrixResult<Option<Member>> {
+        let event = self.get_event("SELECT event FROM gm_room_state WHERE room_id = ?1 AND event_type = 'm.room.member' AND state_key = ?2",
+                                   params![room.id, user_id])?;
+        match event.map(|e| e.content) {
+            Some(Content::RoomMember(m)) => Ok(Some(m)),
+            _ => Ok(None)
+        }
+    }
+    fn account_data(&self, event_type: &str) -> MatrixResult<Option<Event>> {
+        self.get_event("SELECT event FROM gm_account_data WHERE room_id = '' AND event_type = ?1",
+                       params![event_type])
+    }
+    fn room_account_data(&self, room: &Room<'_>, event_type: &str) -> MatrixResult<Option<Event>> {
+        self.get_event("SELECT event FROM gm_account_data WHERE room_id = ?1 AND event_type = ?2",
+                       params![room.id, event_type])
+    }
+    fn add_pending_transaction(&self, txn: &PendingTransaction) -> MatrixResult<()> {
+        let conn = self.conn.lock().unwrap();
+        // `INSERT OR REPLACE` would delete the old row and give the new one a
+        // new rowid, moving it to the end of `pending_transactions`.
+        conn.execute("INSERT INTO gm_pending_transactions (txn_id, room_id, event_type, content) VALUES (?1, ?2, ?3, ?4)
+                      ON CONFLICT (txn_id) DO UPDATE SET room_id = excluded.room_id, event_type = excluded.event_type, content = excluded.content",
+                     params![txn.txn_id, txn.room.id, txn.event_type, ::serde_json::to_string(&txn.content)?])?;
+        Ok(())
+    }
+    fn remove_pending_transaction(&self, txn_id: &str) -> MatrixResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM gm_pending_transactions WHERE txn_id = ?1", params![txn_id])?;
+        Ok(())
+    }
+    fn pending_transactions(&self) -> MatrixResult<Vec<PendingTransaction>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT txn_id, room_id, event_type, content FROM gm_pending_transactions ORDER BY rowid")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?))
+        })?.collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter()
+            .map(|(txn_id, room_id, event_type, content)| {
+                Ok(PendingTransaction {
+                    room: Room::from_id(room_id),
+                    txn_id,
+                    event_type,
+                    content: ::serde_json::from_str(&content)?
+                })
+            })
+            .collect()
+    }
+}
@@ -1,25 +1,181 @@
 //! Utilities for using the long-polling `/sync` API.
 
 use types::sync::*;
+use types::filter::Filter;
 use std::collections::HashMap;
 use crate::request::{MatrixRequest, MatrixRequestable, TypedApiResponse};
-use crate::request::apis::r0::ClientApi;
-use futures::*;
+use crate::request::apis::negotiated::ClientApi;
+use futures::Stream;
 use crate::errors::*;
+use crate::session::Session;
+use crate::state::RoomStates;
+use crate::store::StateStore;
+use crate::MatrixClient;
 use http::Method;
-use futures::Future;
+use futures::ready;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Sleep;
 
+/// A filter to apply to `/sync` requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyncFilter {
+    /// A filter sent along with every request.
+    Inline(Box<Filter>),
+    /// The ID of a filter uploaded with `Filters::upload`.
+    Id(String)
+}
+impl From<Filter> for SyncFilter {
+    fn from(f: Filter) -> Self {
+        SyncFilter::Inline(Box::new(f))
+    }
+}
+impl From<String> for SyncFilter {
+    fn from(id: String) -> Self {
+        SyncFilter::Id(id)
+    }
+}
+impl<'a> From<&'a str> for SyncFilter {
+    fn from(id: &'a str) -> Self {
+        SyncFilter::Id(id.to_string())
+    }
+}
+/// Somewhere to persist the `next_batch` token of a `SyncStream`, so it can
+/// carry on where it left off after a restart.
+///
+/// Any `FnMut(&str)` closure can be used as a store that only saves tokens.
+pub trait SyncTokenStore {
+    /// Load the saved token, if there is one.
+    fn load(&mut self) -> Option<String> {
+        None
+    }
+    /// Save the token of a reply that's been completely processed.
+    fn save(&mut self, token: &str);
+}
+impl<F> SyncTokenStore for F where F: FnMut(&str) {
+    fn save(&mut self, token: &str) {
+        self(token)
+    }
+}
+/// How much longer than the long-polling timeout we wait for a `/sync` reply.
+const SYNC_TIMEOUT_MARGIN: Duration = Duration::from_secs(30);
+/// How long we wait for the reply to an initial (or `full_state`) `/sync`,
+/// which can take the homeserver a long time to put together for big
+/// accounts.
+///
+/// This is much longer than the client's default request timeout, because a
+/// timeout counts as a transient error: if the homeserver always took longer
+/// than the timeout, the stream would never get anywhere.
+const INITIAL_SYNC_TIMEOUT: Duration = Duration::from_secs(600);
+/// Controls how a `SyncStream` backs off and retries after transient errors
+/// (see `MatrixError::is_transient`).
+///
+/// The delay starts at `base_delay`, doubling after each consecutive failure
+/// up to `max_delay`, and is then randomly reduced by up to half so that lots
+/// of clients don't all come back at once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncBackoff {
+    /// Delay before the first retry.
+    pub base_delay: Duration,
+    /// Maximum delay between retries.
+    pub max_delay: Duration,
+    /// How many times in a row to retry before giving up and yielding the
+    /// error from the stream, or `None` to keep retrying forever.
+    pub max_retries: Option<u32>
+}
+impl Default for SyncBackoff {
+    /// Starts at 1 second, goes up to 1 minute, and never gives up.
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_retries: None
+        }
+    }
+}
+impl SyncBackoff {
+    /// Never retry: yield every error from the stream straight away.
+    pub fn never() -> Self {
+        Self {
+            max_retries: Some(0),
+            ..Self::default()
+        }
+    }
+    /// Get the delay before retrying after `failures` consecutive failures, or
+    /// `None` if we shouldn't retry.
+    fn retry_delay(&self, failures: u32) -> Option<Duration> {
+        if self.max_retries.map(|m| failures > m).unwrap_or(false) {
+            return None;
+        }
+        let delay = self.base_delay
+            .checked_mul(2u32.saturating_pow(failures - 1))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        // `RandomState` is seeded randomly, which is plenty for jitter.
+        let rand = RandomState::new().build_hasher().finish();
+        let half = delay / 2;
+        Some(half + half.mul_f64((rand % 1024) as f64 / 1024.0))
+    }
+}
+/// The state of a `SyncStream`'s connection to the homeserver, as reported by
+/// `SyncStream::connection_state`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// No request has finished yet.
+    Connecting,
+    /// The last request succeeded.
+    Connected,
+    /// The last request failed with a transient error, and will be retried.
+    Retrying {
+        /// How many requests in a row have failed.
+        failures: u32,
+        /// How long until the next attempt.
+        delay: Duration,
+        /// The error the last request failed with.
+        error: String
+    },
+    /// The last request failed, and the error was yielded from the stream.
+    ///
+    /// Polling the stream again will try again anyway.
+    Failed(String)
+}
 /// A `Stream` that yields constant replies to `/sync`.
 ///
 /// This calls the long-polling `/sync` API, which will wait until replies come
 /// in and send them to the client. If you want to reduce the wait time, use the
 /// `set_timeout()` function.
+///
+/// If a request fails with a transient error (e.g. the connection dropped),
+/// the stream waits a bit and tries again from the last successful reply (see
+/// `SyncBackoff`). Other errors, such as our access token being invalidated,
+/// are yielded from the stream; polling it again will try again anyway. Use
+/// `connection_state` to find out what's going on in the meantime.
+///
+/// To avoid doing an initial sync (and seeing old events again) every time
+/// your program starts, save the `next_batch` token somewhere and start from
+/// it next time, with `new_from_token` or a `SyncTokenStore`.
 pub struct SyncStream<R> where R: MatrixRequestable {
     pub(crate) rq: R,
     pub(crate) last_batch: Option<String>,
     pub(crate) set_presence: bool,
     pub(crate) timeout: u64,
-    pub(crate) cur_req: Option<TypedApiResponse<SyncReply, R::SendRequestFuture, R::ResponseBodyFuture>>
+    pub(crate) filter: Option<SyncFilter>,
+    pub(crate) full_state: bool,
+    pub(crate) token_store: Option<Box<dyn SyncTokenStore + Send>>,
+    pub(crate) room_states: Option<RoomStates>,
+    pub(crate) state_store: Option<Arc<dyn StateStore>>,
+    pub(crate) unsaved: bool,
+    pub(crate) backoff: SyncBackoff,
+    pub(crate) failures: u32,
+    pub(crate) backoff_sleep: Option<Pin<Box<Sleep>>>,
+    pub(crate) state: watch::Sender<ConnectionState>,
+    pub(crate) cur_req: Option<TypedApiResponse<SyncReply, R::SendRequestFuture>>
 }
 impl<R> SyncStream<R> where R: MatrixRequestable {
     /// Make a new `SyncStream` from a given `MatrixRequestable`.
@@ -29,9 +185,133 @@ impl<R> SyncStream<R> where R: MatrixRequestable {
             last_batch: None,
             set_presence: true,
             timeout: 30_000,
+            filter: None,
+            full_state: false,
+            token_store: None,
+            room_states: None,
+            state_store: None,
+            unsaved: false,
+            backoff: SyncBackoff::default(),
+            failures: 0,
+            backoff_sleep: None,
+            state: watch::channel(ConnectionState::Connecting).0,
             cur_req: None
         }
     }
+    /// Make a new `SyncStream` that carries on from a `next_batch` token
+    /// returned by a previous one.
+    pub fn new_from_token(rq: R, token: String) -> Self {
+        let mut ret = Self::new(rq);
+        ret.last_batch = Some(token);
+        ret
+    }
+    /// Make a new `SyncStream` that carries on from where a saved `Session`
+    /// left off (i.e. from its `sync_token`, if there is one).
+    pub fn new_from_session(rq: R, session: &Session) -> Self {
+        let mut ret = Self::new(rq);
+        ret.last_batch = session.sync_token.clone();
+        ret
+    }
+    /// Get the `next_batch` token of the last reply this stream returned (or
+    /// the one it was started from), if any.
+    ///
+    /// The next request carries on from here.
+    pub fn next_batch(&self) -> Option<&str> {
+        self.last_batch.as_ref().map(|x| x as &str)
+    }
+    /// Set a store to save the `next_batch` token to.
+    ///
+    /// The token of each reply is saved once that reply has been processed,
+    /// which is taken to be when the stream is next polled (or when
+    /// `save_token` is called). If this stream doesn't have a token yet, the
+    /// store's saved one (if any) is loaded.
+    pub fn set_token_store<S: SyncTokenStore + Send + 'static>(&mut self, mut store: S) {
+        if self.last_batch.is_none() {
+            self.last_batch = store.load();
+        }
+        self.token_store = Some(Box::new(store));
+    }
+    /// Save the token of the last reply this stream returned to the token store
+    /// now, rather than waiting until the stream is next polled.
+    ///
+    /// Call this if you're about to drop the stream after processing a reply,
+    /// or that reply will be seen again next time.
+    pub fn save_token(&mut self) {
+        if !self.unsaved {
+            return;
+        }
+        if let (Some(store), Some(tok)) = (self.token_store.as_mut(), self.last_batch.as_ref()) {
+            store.save(tok);
+        }
+        self.unsaved = false;
+    }
+    /// Get the room states this stream keeps up to date, if any.
+    pub fn room_states(&self) -> Option<&RoomStates> {
+        self.room_states.as_ref()
+    }
+    /// Set a `RoomStates` to keep up to date with the state events in every
+    /// reply, or `None` to stop.
+    ///
+    /// Each reply is applied before it's returned from the stream, so the
+    /// states are current by the time you see it. If this stream carries on
+    /// from a saved token, the states should be too, or they'll be missing
+    /// what happened before it; use `set_full_state` to get it all again.
+    pub fn set_room_states(&mut self, states: Option<RoomStates>) {
+        self.room_states = states;
+    }
+    /// Get the state store this stream saves replies to, if any.
+    pub fn state_store(&self) -> Option<&Arc<dyn StateStore>> {
+        self.state_store.as_ref()
+    }
+    /// Set a `StateStore` to save every reply to, or `None` to stop.
+    ///
+    /// Each reply is saved before it's returned from the stream, along with
+    /// its `next_batch` token, so (unlike with a `SyncTokenStore`) a reply
+    /// that was being processed when the program stopped won't be seen again.
+    /// If saving fails, the error is returned from the stream instead of the
+    /// reply, and the same reply is asked for again next time.
+    ///
+    /// If this stream doesn't have a token yet, the store's saved one (if any)
+    /// is loaded.
+    pub fn set_state_store(&mut self, store: Option<Arc<dyn StateStore>>) -> MatrixResult<()> {
+        if let Some(ref s) = store {
+            if self.last_batch.is_none() {
+                self.last_batch = s.sync_token()?;
+            }
+        }
+        self.state_store = store;
+        Ok(())
+    }
+    /// Get the policy used to retry after transient errors.
+    pub fn backoff(&self) -> &SyncBackoff {
+        &self.backoff
+    }
+    /// Set the policy used to retry after transient errors.
+    ///
+    /// See the `SyncBackoff` docs for the default.
+    pub fn set_backoff(&mut self, backoff: SyncBackoff) {
+        self.backoff = backoff;
+    }
+    /// Get a receiver that's told about changes to the stream's connection
+    /// state, and can be used from another task.
+    pub fn connection_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+    /// Ascertain whether `/sync` requests ask for the full state of every
+    /// room, even when carrying on from a previous reply.
+    ///
+    /// The default value is `false`.
+    pub fn full_state(&self) -> bool {
+        self.full_state
+    }
+    /// Set whether `/sync` requests ask for the full state of every room, even
+    /// when carrying on from a previous reply.
+    ///
+    /// This applies to every request until it's turned off again, so you
+    /// probably want to set it back to `false` after the first reply.
+    pub fn set_full_state(&mut self, v: bool) {
+        self.full_state = v;
+    }
     /// Set whether polling the `/sync` API marks us as online.
     pub fn set_sync_sets_presence(&mut self, v: bool) {
         self.set_presence = v;
@@ -52,58 +332,136 @@ impl<R> SyncStream<R> where R: MatrixRequestable {
     ///
     /// The default value is `30000` (30 seconds).
     ///
-    /// This does not guard against other problems, such as connection loss;
-    /// this merely *asks* the HS for a given timeout.
+    /// This merely *asks* the HS for a given timeout; to guard against other
+    /// problems, such as connection loss, we also give up on the request
+    /// ourselves if there's no reply within this timeout plus a 30-second
+    /// margin. Initial syncs (and `full_state` ones) don't long-poll, but can
+    /// take a while for big accounts, so we wait up to 10 minutes for them
+    /// instead, whatever the client's default request timeout is.
     pub fn set_timeout(&mut self, timeout: u64) {
         self.timeout = timeout;
     }
-    fn req(&mut self) -> MatrixRequest<'static, ()> {
+    /// Get the filter applied to `/sync` requests, if any.
+    pub fn filter(&self) -> Option<&SyncFilter> {
+        self.filter.as_ref()
+    }
+    /// Set a filter to apply to `/sync` requests: either an inline `Filter`,
+    /// or the ID of an uploaded one (as a `String` or `&str`).
+    ///
+    /// This takes effect from the next request; pass `None` to remove it.
+    pub fn set_filter<F: Into<SyncFilter>>(&mut self, filter: Option<F>) {
+        self.filter = filter.map(|f| f.into());
+    }
+    fn req(&self) -> MatrixRequest<'static, ()> {
         let mut params = HashMap::new();
+        let mut timeout = None;
         params.insert("set_presence".into(), if self.set_presence {
             "online"
         } else { "offline" }.to_string().into());
         if let Some(ref b) = self.last_batch {
             params.insert("since".into(), b.to_string().into());
             params.insert("timeout".into(), self.timeout.to_string().into());
+            timeout = Some(Duration::from_millis(self.timeout) + SYNC_TIMEOUT_MARGIN);
+        }
+        if self.full_state {
+            params.insert("full_state".into(), "true".into());
+        }
+        if self.last_batch.is_none() || self.full_state {
+            timeout = timeout.max(Some(INITIAL_SYNC_TIMEOUT));
+        }
+        match self.filter {
+            Some(SyncFilter::Inline(ref f)) => {
+                let f = ::serde_json::to_string(f)
+                    .expect("filters are always serializable");
+                params.insert("filter".into(), f.into());
+            },
+            Some(SyncFilter::Id(ref id)) => {
+                params.insert("filter".into(), id.clone().into());
+            },
+            None => {}
         }
         MatrixRequest {
             meth: Method::GET,
             endpoint: "/sync".into(),
             params,
             body: (),
-            typ: ClientApi
+            typ: ClientApi,
+            timeout
         }
     }
 }
 
-impl<R> Stream for SyncStream<R> where R: MatrixRequestable {
-    type Item = SyncReply;
-    type Error = MatrixError;
+impl SyncStream<MatrixClient> {
+    /// Export the client's session (see `MatrixClient::session`), along with the
+    /// token of the last `/sync` reply this stream returned.
+    pub fn session(&self) -> Session {
+        Session {
+            sync_token: self.last_batch.clone(),
+            ..self.rq.session()
+        }
+    }
+}
+impl<R> Stream for SyncStream<R> where R: MatrixRequestable + Unpin {
+    type Item = MatrixResult<SyncReply>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // Being polled again means the caller's done with the last reply.
+        this.save_token();
         loop {
-            if self.cur_req.is_some() {
-                match self.cur_req.as_mut().unwrap().poll() {
-                    Ok(Async::Ready(rpl)) => {
-                        self.last_batch = Some(rpl.next_batch.clone());
-                        self.cur_req = None;
-                        return Ok(Async::Ready(Some(rpl)));
+            if let Some(ref mut sleep) = this.backoff_sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.backoff_sleep = None;
+            }
+            if let Some(ref mut cur_req) = this.cur_req {
+                match Pin::new(cur_req).poll(cx) {
+                    Poll::Ready(Ok(rpl)) => {
+                        if let Some(ref store) = this.state_store {
+                            if let Err(e) = store.save_sync(&rpl) {
+                                this.cur_req = None;
+                                this.state.send_replace(ConnectionState::Failed(e.to_string()));
+                                return Poll::Ready(Some(Err(e)));
+                            }
+                        }
+                        this.last_batch = Some(rpl.next_batch.clone());
+                        this.unsaved = true;
+                        this.cur_req = None;
+                        this.failures = 0;
+                        this.state.send_replace(ConnectionState::Connected);
+                        if let Some(ref states) = this.room_states {
+                            states.apply_sync(&rpl);
+                        }
+                        return Poll::Ready(Some(Ok(rpl)));
                     },
-                    Ok(Async::NotReady) => {
-                        return Ok(Async::NotReady);
+                    Poll::Ready(Err(e)) => {
+                        this.cur_req = None;
+                        this.failures += 1;
+                        if e.is_transient() {
+                            if let Some(delay) = this.backoff.retry_delay(this.failures) {
+                                this.state.send_replace(ConnectionState::Retrying {
+                                    failures: this.failures,
+                                    delay,
+                                    error: e.to_string()
+                                });
+                                this.backoff_sleep = Some(Box::pin(tokio::time::sleep(delay)));
+                                continue;
+                            }
+                        }
+                        this.failures = 0;
+                        this.state.send_replace(ConnectionState::Failed(e.to_string()));
+                        return Poll::Ready(Some(Err(e)));
                     },
-                    Err(e) => {
-                        return Err(e);
+                    Poll::Pending => {
+                        return Poll::Pending;
                     }
                 }
             }
-            let req = self.req();
-            let req = match req.make_request(&mut self.rq) {
+            let req = this.req();
+            let req = match req.make_request(&this.rq) {
                 Ok(r) => r,
-                Err(e) => return Err(e.into())
+                Err(e) => return Poll::Ready(Some(Err(e)))
             };
-            self.cur_req = Some(self.rq.typed_api_call(req, false));
+            this.cur_req = Some(this.rq.typed_api_call(req, false));
         }
     }
 }
-
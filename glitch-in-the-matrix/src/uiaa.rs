@@ -0,0 +1,169 @@
+//! User-Interactive Authentication (UIAA), for endpoints that need more than
+//! just an access token.
+//!
+//! Protected endpoints respond to a request with the ways it can be
+//! authenticated, as a `UiaaInfo`. The caller then completes the stages of one
+//! of those flows, one at a time, by sending the request again with some
+//! `AuthData`, until the homeserver is happy and actually does what was asked.
+//! `UiaaRequest` keeps track of all that.
+
+use crate::errors::*;
+use crate::login::UserIdentifier;
+use crate::request::{MatrixRequest, MatrixRequestable};
+use crate::types::replies::UiaaInfo;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+/// Authentication for one UIAA stage.
+#[derive(Clone, Debug)]
+pub enum AuthData {
+    /// `m.login.dummy`: nothing, for flows that don't actually need any.
+    Dummy,
+    /// `m.login.password`: the user's password.
+    Password {
+        /// Who's authenticating.
+        identifier: UserIdentifier,
+        /// Their password.
+        password: String
+    },
+    /// `m.login.recaptcha`: the response from a Google ReCaptcha widget, set
+    /// up with the `public_key` from the stage's params.
+    Recaptcha(String),
+    /// `m.login.email.identity`: proof that the user owns an email address,
+    /// from following a link sent to it by a `requestToken` endpoint.
+    EmailIdentity {
+        /// The session ID the `requestToken` endpoint returned.
+        sid: String,
+        /// The client secret sent to the `requestToken` endpoint.
+        client_secret: String,
+        /// The identity server used, if the homeserver delegated to one.
+        id_server: Option<String>,
+        /// An access token for that identity server.
+        id_access_token: Option<String>
+    },
+    /// `m.login.registration_token`: a token from the homeserver admin
+    /// allowing registration.
+    RegistrationToken(String),
+    /// Some other login type, with the given extra fields.
+    Other(String, Map<String, Value>)
+}
+impl AuthData {
+    /// Get the login type of this stage (e.g. `m.login.dummy`).
+    pub fn login_type(&self) -> &str {
+        match *self {
+            AuthData::Dummy => "m.login.dummy",
+            AuthData::Password { .. } => "m.login.password",
+            AuthData::Recaptcha(_) => "m.login.recaptcha",
+            AuthData::EmailIdentity { .. } => "m.login.email.identity",
+            AuthData::RegistrationToken(_) => "m.login.registration_token",
+            AuthData::Other(ref typ, _) => typ
+        }
+    }
+    /// Make the `auth` object to send, for the given session.
+    pub fn to_json(&self, session: Option<&str>) -> Value {
+        let mut ret = match *self {
+            AuthData::Dummy => json!({}),
+            AuthData::Password { ref identifier, ref password } => json!({
+                "identifier": identifier,
+                "password": password
+            }),
+            AuthData::Recaptcha(ref response) => json!({
+                "response": response
+            }),
+            AuthData::EmailIdentity { ref sid, ref client_secret, ref id_server, ref id_access_token } => {
+                let mut creds = json!({
+                    "sid": sid,
+                    "client_secret": client_secret
+                });
+                if let Some(ref is) = *id_server {
+                    creds["id_server"] = json!(is);
+                }
+                if let Some(ref tok) = *id_access_token {
+                    creds["id_access_token"] = json!(tok);
+                }
+                json!({ "threepid_creds": creds })
+            },
+            AuthData::RegistrationToken(ref token) => json!({
+                "token": token
+            }),
+            AuthData::Other(_, ref fields) => Value::Object(fields.clone())
+        };
+        ret["type"] = json!(self.login_type());
+        if let Some(sess) = session {
+            ret["session"] = json!(sess);
+        }
+        ret
+    }
+}
+/// The outcome of sending a `UiaaRequest`.
+#[derive(Clone, Debug)]
+pub enum UiaaResponse<R> {
+    /// The request went through, and this is what the homeserver replied.
+    Done(R),
+    /// The request needs (more) authentication; complete one of `UiaaInfo::next_stages`
+    /// and send it again.
+    AuthRequired(UiaaInfo)
+}
+/// A request to an endpoint protected by User-Interactive Authentication.
+///
+/// Send it once without any `AuthData` to find out which flows are available,
+/// and then once for each stage of the flow you choose. The session ID is
+/// remembered and passed back automatically.
+pub struct UiaaRequest<'a> {
+    req: MatrixRequest<'a, Value>,
+    info: Option<UiaaInfo>
+}
+impl<'a> UiaaRequest<'a> {
+    /// Wrap a request, whose body must be a JSON object (the `auth` field is
+    /// filled in as required).
+    pub fn new(req: MatrixRequest<'a, Value>) -> Self {
+        Self { req, info: None }
+    }
+    /// Get what the homeserver last told us about the authentication it
+    /// wants, if we've sent the request yet.
+    pub fn info(&self) -> Option<&UiaaInfo> {
+        self.info.as_ref()
+    }
+    fn set_auth(&mut self, auth: Option<AuthData>) {
+        let session = self.info.as_ref().and_then(|i| i.session.as_ref());
+        match auth {
+            Some(auth) => {
+                self.req.body["auth"] = auth.to_json(session.map(|s| s as &str));
+            },
+            None => {
+                if let Value::Object(ref mut obj) = self.req.body {
+                    obj.remove("auth");
+                }
+            }
+        }
+    }
+    fn handle_result<R>(&mut self, res: MatrixResult<R>) -> MatrixResult<UiaaResponse<R>> {
+        match res {
+            Ok(r) => Ok(UiaaResponse::Done(r)),
+            Err(MatrixError::AuthRequired(info)) => {
+                self.info = Some((*info).clone());
+                Ok(UiaaResponse::AuthRequired(*info))
+            },
+            Err(e) => Err(e)
+        }
+    }
+    /// Send the request, completing the given stage (or, if `auth` is `None`,
+    /// just to find out which stages are needed).
+    ///
+    /// If the stage failed, the reply is `AuthRequired` again, with the
+    /// homeserver's error code filled in.
+    pub async fn send<C, R>(&mut self, rq: &C, auth: Option<AuthData>) -> MatrixResult<UiaaResponse<R>>
+        where C: MatrixRequestable, R: DeserializeOwned + 'static {
+        self.set_auth(auth);
+        let res = self.req.send(rq).await;
+        self.handle_result(res)
+    }
+    /// Like `send`, but throws away the homeserver's reply once the request
+    /// goes through.
+    pub async fn discarding_send<C>(&mut self, rq: &C, auth: Option<AuthData>) -> MatrixResult<UiaaResponse<()>>
+        where C: MatrixRequestable {
+        self.set_auth(auth);
+        let res = self.req.discarding_send(rq).await;
+        self.handle_result(res)
+    }
+}
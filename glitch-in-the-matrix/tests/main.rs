@@ -1,16 +1,122 @@
 extern crate glitch_in_the_matrix as matrix_api;
+extern crate futures;
 extern crate serde_json;
 
-use matrix_api::types::sync::SyncReply;
+use matrix_api::{MatrixClient, MatrixClientBuilder};
+use matrix_api::http::{Method, Request, Response};
+use matrix_api::http::header::AUTHORIZATION;
+use matrix_api::account::Account;
+use matrix_api::devices::Devices;
+use matrix_api::discovery::DiscoveredServer;
+use matrix_api::media::Media;
+use matrix_api::dispatch::{Dispatcher, HandlerError};
+use matrix_api::state::{RoomState, RoomStates};
+use matrix_api::store::{MemoryStore, PendingTransaction, StateStore};
+use matrix_api::types::room::Room;
+use matrix_api::types::content::Content;
+use matrix_api::types::content::room::types::{JoinRule, Membership};
+use matrix_api::filter::Filters;
+use matrix_api::sync::{ConnectionState, SyncBackoff, SyncStream};
+use matrix_api::types::filter::{Filter, RoomEventFilter, RoomFilter};
+use futures::StreamExt;
+use matrix_api::login::{SsoCallbackListener, UserIdentifier};
+use matrix_api::session::Session;
+use matrix_api::uiaa::{AuthData, UiaaRequest, UiaaResponse};
+use matrix_api::request::{is_idempotent, AccessTokenMode, ApiVersion, MatrixRequest, MatrixRequestable, RequestTimeout, RetryPolicy};
+use matrix_api::errors::{MatrixError, MatrixResult};
+use matrix_api::types::replies::{BadRequestReply, MatrixErrorCode, UiaaInfo, VersionsReply};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::time::Duration;
+use matrix_api::types::sync::{EventSource, RoomCategory, SyncReply};
 use matrix_api::types::events::Event;
+use matrix_api::types::messages::Message;
+use matrix_api::room::RoomExt;
 
 use std::fs;
 use std::io;
 use std::path;
 
+/// A `MatrixRequestable` that doesn't talk to a homeserver.
+///
+/// Clones share the same canned responses and request logs.
+#[derive(Clone)]
+struct MockClient {
+    url: String,
+    mode: AccessTokenMode,
+    is_as: bool,
+    version: ApiVersion,
+    access_token: &'static str,
+    /// Canned responses (status code and body) to give out, in order; once
+    /// they run out, requests get `200 {}`.
+    replies: Rc<RefCell<VecDeque<(u16, &'static str)>>>,
+    /// The bodies of the requests sent so far.
+    sent: Rc<RefCell<Vec<serde_json::Value>>>,
+    /// The URIs of the requests sent so far.
+    uris: Rc<RefCell<Vec<String>>>,
+    /// The timeouts (see `RequestTimeout`) of the requests sent so far.
+    timeouts: Rc<RefCell<Vec<Option<Duration>>>>
+}
+impl MockClient {
+    fn new() -> Self {
+        MockClient {
+            url: "https://example.org".into(),
+            mode: AccessTokenMode::Header,
+            is_as: false,
+            version: ApiVersion::R0,
+            access_token: "s3kr1t",
+            replies: Rc::new(RefCell::new(VecDeque::new())),
+            sent: Rc::new(RefCell::new(vec![])),
+            uris: Rc::new(RefCell::new(vec![])),
+            timeouts: Rc::new(RefCell::new(vec![]))
+        }
+    }
+}
+impl MatrixRequestable for MockClient {
+    type Txnid = u32;
+    type ResponseBody = Vec<u8>;
+    type SendRequestFuture = futures::future::Ready<MatrixResult<Response<Vec<u8>>>>;
+
+    fn get_url(&self) -> Cow<'_, str> {
+        (&self.url as &str).into()
+    }
+    fn is_as(&self) -> bool {
+        self.is_as
+    }
+    fn get_access_token(&self) -> Cow<'_, str> {
+        self.access_token.into()
+    }
+    fn access_token_mode(&self) -> AccessTokenMode {
+        self.mode
+    }
+    fn api_version(&self) -> ApiVersion {
+        self.version
+    }
+    fn get_user_id(&self) -> Cow<'_, str> {
+        "@bot:example.org".into()
+    }
+    fn get_txnid(&self) -> u32 {
+        0
+    }
+    fn send_request(&self, req: Request<Vec<u8>>) -> Self::SendRequestFuture {
+        self.uris.borrow_mut().push(req.uri().to_string());
+        self.timeouts.borrow_mut().push(req.extensions().get::<RequestTimeout>().map(|t| t.0));
+        self.sent.borrow_mut().push(serde_json::from_slice(req.body()).unwrap_or(serde_json::Value::Null));
+        let (status, body) = self.replies.borrow_mut().pop_front().unwrap_or((200, "{}"));
+        futures::future::ready(Ok(Response::builder()
+                                  .status(status)
+                                  .body(body.as_bytes().to_vec())
+                                  .unwrap()))
+    }
+}
+
+#[allow(clippy::expect_fun_call)]
 fn read_file(file: &str) -> String {
     let mut text = String::new();
-    let mut f = fs::File::open(&file)
+    let mut f = fs::File::open(file)
         .expect(&format!("File not found: '{}'!",file));
     io::Read::read_to_string(&mut f,&mut text).expect("something went wrong reading the file");
     text
@@ -18,6 +124,7 @@ fn read_file(file: &str) -> String {
 }
 
 #[test]
+#[allow(clippy::needless_borrow)]
 fn deser_events() {
     let mut failed = false;
     let rd: fs::ReadDir = fs::read_dir("tests/event-examples").unwrap();
@@ -65,3 +172,934 @@ fn deser_sync() {
         println!("test deser_sync: sucessfully parsed sync {}!",v);
     }
 }
+
+#[test]
+fn deser_sync_extras() {
+    let sync: SyncReply = serde_json::from_str(&read_file("tests/sync_v3.json")).unwrap();
+    assert_eq!(sync.to_device.events[0].event_type, "m.new_device");
+    assert_eq!(sync.device_lists.changed, vec!["@alice:example.com".to_string()]);
+    assert_eq!(sync.device_lists.left, vec!["@bob:example.com".to_string()]);
+    assert_eq!(sync.device_one_time_keys_count["signed_curve25519"], 20);
+    assert_eq!(sync.device_unused_fallback_key_types, Some(vec!["signed_curve25519".to_string()]));
+
+    let joined = sync.rooms.join.values().next().unwrap();
+    assert_eq!(joined.summary.heroes.as_ref().unwrap().len(), 2);
+    assert_eq!(joined.summary.joined_member_count, Some(2));
+    assert_eq!(joined.summary.invited_member_count, Some(0));
+    assert_eq!(joined.timeline.prev_batch, None);
+
+    let left = sync.rooms.leave.values().next().unwrap();
+    assert_eq!(left.timeline.prev_batch.as_ref().map(|x| x as &str), Some("t34-23535_0_0"));
+    assert_eq!(left.account_data.events[0].event_type, "m.tag");
+    assert_eq!(sync.rooms.knock.values().next().unwrap().knock_state.events.len(), 1);
+}
+
+#[test]
+fn sync_event_sources() {
+    let sync: SyncReply = serde_json::from_str(&read_file("tests/sync_v3.json")).unwrap();
+    let events = sync.events()
+        .map(|e| (e.source, e.category, e.room.map(|r| r.id.to_string()), e.event.event_type.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(events, vec![
+        (EventSource::ToDevice, None, None, "m.new_device".to_string()),
+        (EventSource::Timeline, Some(RoomCategory::Joined), Some("!726s6s6q:example.com".to_string()), "m.room.message".to_string()),
+        (EventSource::KnockState, Some(RoomCategory::Knocked), Some("!knock:example.com".to_string()), "m.room.name".to_string()),
+        (EventSource::RoomAccountData, Some(RoomCategory::Left), Some("!left:example.com".to_string()), "m.tag".to_string())
+    ]);
+    assert_eq!(sync.iter_events().count(), 1);
+}
+
+#[test]
+fn client_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync + Clone + 'static>() {}
+    assert_send_sync::<MatrixClient>();
+}
+
+#[test]
+fn access_token_modes() {
+    let mut cli = MockClient::new();
+    cli.is_as = true;
+    let mut req = MatrixRequest::new_basic(Method::GET, "/sync");
+    req.params.insert("since".into(), "s1".into());
+    req.params.insert("filter".into(), r#"{"types":["a&b=c+d"]}"#.into());
+
+    let hdr = req.make_request(&cli).unwrap();
+    assert_eq!(hdr.headers()[AUTHORIZATION], "Bearer s3kr1t");
+    assert!(!hdr.uri().to_string().contains("s3kr1t"));
+    assert!(hdr.uri().to_string().contains("user_id=%40bot%3Aexample.org"));
+    assert!(hdr.uri().to_string().contains("filter=%7B%22types%22%3A%5B%22a%26b%3Dc%2Bd%22%5D%7D"));
+
+    cli.mode = AccessTokenMode::QueryString;
+    let qs = req.make_request(&cli).unwrap();
+    assert!(qs.headers().get(AUTHORIZATION).is_none());
+    assert!(qs.uri().to_string().contains("access_token=s3kr1t"));
+
+    cli.access_token = "a&b+c%d";
+    let odd = req.make_request(&cli).unwrap();
+    assert!(odd.uri().to_string().contains("access_token=a%26b%2Bc%25d&"));
+}
+
+#[test]
+fn rate_limit_retries() {
+    let limited = r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests", "retry_after_ms": 2000, "foo": 1}"#;
+    let brr: BadRequestReply = serde_json::from_str(limited).unwrap();
+    assert_eq!(brr.retry_after_ms, Some(2000));
+    assert_eq!(brr.extra["foo"], 1);
+
+    let policy = RetryPolicy::default();
+    let resp = Response::builder()
+        .status(429)
+        .body(limited.as_bytes().to_vec())
+        .unwrap();
+    assert_eq!(policy.retry_delay(1, false, &resp), Some(Duration::from_millis(2000)));
+    assert_eq!(policy.retry_delay(policy.max_attempts, true, &resp), None);
+    assert_eq!(RetryPolicy::never().retry_delay(1, true, &resp), None);
+
+    let header = Response::builder()
+        .status(429)
+        .header("Retry-After", "3")
+        .body(vec![])
+        .unwrap();
+    assert_eq!(policy.retry_delay(1, false, &header), Some(Duration::from_secs(3)));
+
+    let too_long = Response::builder()
+        .status(429)
+        .header("Retry-After", "3600")
+        .body(vec![])
+        .unwrap();
+    assert_eq!(policy.retry_delay(1, true, &too_long), None);
+
+    // Without a delay from the server, we back off exponentially, but never
+    // for longer than `max_delay`.
+    let unspecified = Response::builder()
+        .status(429)
+        .body(br#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests"}"#.to_vec())
+        .unwrap();
+    let patient = RetryPolicy {
+        max_attempts: 20,
+        ..RetryPolicy::default()
+    };
+    assert_eq!(patient.retry_delay(1, false, &unspecified), Some(patient.base_delay));
+    assert_eq!(patient.retry_delay(3, false, &unspecified), Some(patient.base_delay * 4));
+    assert_eq!(patient.retry_delay(15, false, &unspecified), Some(patient.max_delay));
+
+    let bad_gateway = Response::builder()
+        .status(502)
+        .body(vec![])
+        .unwrap();
+    assert_eq!(policy.retry_delay(1, true, &bad_gateway), Some(policy.base_delay));
+    assert_eq!(policy.retry_delay(2, true, &bad_gateway), Some(policy.base_delay * 2));
+    assert_eq!(patient.retry_delay(15, true, &bad_gateway), Some(patient.max_delay));
+    // The homeserver might have acted on a non-idempotent request before the
+    // error, so it mustn't be sent again.
+    assert_eq!(policy.retry_delay(1, false, &bad_gateway), None);
+    assert!(is_idempotent(&Method::GET, "/_matrix/client/v3/sync"));
+    assert!(is_idempotent(&Method::PUT, "/_matrix/client/v3/rooms/!a:b/send/m.room.message/1"));
+    assert!(!is_idempotent(&Method::PUT, "/_matrix/client/v3/rooms/!a:b/state/m.room.name/"));
+    assert!(!is_idempotent(&Method::POST, "/_matrix/client/v3/createRoom"));
+    assert!(!is_idempotent(&Method::POST, "/_matrix/media/v3/upload"));
+
+    let not_found = Response::builder()
+        .status(404)
+        .body(vec![])
+        .unwrap();
+    assert_eq!(policy.retry_delay(1, true, &not_found), None);
+}
+
+/// Discover a server with `DiscoveredServer::discover_using`, making requests
+/// with clones of `cli` pointed at whatever URL discovery asks for.
+fn discover_with_mock(cli: &MockClient, name: &str) -> MatrixResult<DiscoveredServer> {
+    futures::executor::block_on(DiscoveredServer::discover_using(name, |url| {
+        let mut cli = cli.clone();
+        cli.url = url;
+        Ok(cli)
+    }))
+}
+#[test]
+fn discovery() {
+    // No `.well-known`, so we use the server name.
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((404, ""));
+    cli.replies.borrow_mut().push_back((200, r#"{"versions": ["v1.1"]}"#));
+    let server = discover_with_mock(&cli, "@alice:example.org").unwrap();
+    assert_eq!(server.homeserver_url, "https://example.org");
+    assert_eq!(server.identity_server_url, None);
+    assert!(server.versions.supports("v1.1"));
+    assert_eq!(*cli.uris.borrow(), vec!["https://example.org/.well-known/matrix/client",
+                                        "https://example.org/_matrix/client/versions"]);
+
+    // A `.well-known` pointing somewhere else.
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((200, r#"{
+        "m.homeserver": {"base_url": "https://matrix.example.org/"},
+        "m.identity_server": {"base_url": "https://id.example.org"}
+    }"#));
+    cli.replies.borrow_mut().push_back((200, r#"{"versions": ["r0.6.1"], "unstable_features": {"org.example.foo": true}}"#));
+    let server = discover_with_mock(&cli, "example.org").unwrap();
+    assert_eq!(server.homeserver_url, "https://matrix.example.org");
+    assert_eq!(server.identity_server_url, Some("https://id.example.org".to_string()));
+    assert!(server.versions.supports("r0.6.1"));
+    assert_eq!(server.versions.unstable_features.get("org.example.foo"), Some(&true));
+    assert_eq!(*cli.uris.borrow(), vec!["https://example.org/.well-known/matrix/client",
+                                        "https://matrix.example.org/_matrix/client/versions"]);
+
+    // A `.well-known` with a broken URL in it.
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((200, r#"{"m.homeserver": {"base_url": "matrix.example.org"}}"#));
+    match discover_with_mock(&cli, "example.org") {
+        Err(MatrixError::DiscoveryFailed(msg)) => assert!(msg.contains("matrix.example.org")),
+        x => panic!("expected discovery to fail, got {:?}", x.map(|s| s.homeserver_url))
+    }
+    assert_eq!(cli.uris.borrow().len(), 1);
+}
+
+#[test]
+fn error_codes() {
+    let soft: BadRequestReply = serde_json::from_str(r#"{"errcode": "M_UNKNOWN_TOKEN", "soft_logout": true}"#).unwrap();
+    assert_eq!(soft.error_code(), MatrixErrorCode::UnknownToken { soft_logout: true });
+    let err = MatrixError::BadRequest(soft);
+    assert!(err.is_unknown_token());
+    assert!(err.is_soft_logout());
+    assert!(!err.is_not_found());
+
+    let uiaa: UiaaInfo = serde_json::from_str(r#"{"flows": [], "errcode": "M_UNKNOWN_TOKEN", "soft_logout": true}"#).unwrap();
+    let err = MatrixError::AuthRequired(Box::new(uiaa));
+    assert!(err.is_soft_logout());
+
+    let custom: BadRequestReply = serde_json::from_str(r#"{"errcode": "COM.EXAMPLE_FOO"}"#).unwrap();
+    assert_eq!(custom.error_code(), MatrixErrorCode::Unknown("COM.EXAMPLE_FOO".into()));
+    assert_eq!(MatrixErrorCode::from("M_USER_IN_USE"), MatrixErrorCode::UserInUse);
+    assert_eq!(MatrixErrorCode::UserInUse.as_str(), "M_USER_IN_USE");
+
+    let limited: BadRequestReply = serde_json::from_str(r#"{"errcode": "M_LIMIT_EXCEEDED"}"#).unwrap();
+    assert!(MatrixError::BadRequest(limited).is_rate_limited());
+    assert!(MatrixError::HttpCode(matrix_api::http::StatusCode::NOT_FOUND).is_not_found());
+}
+
+#[test]
+fn session_roundtrip() {
+    let session: Session = serde_json::from_str(r#"{
+        "homeserver_url": "https://example.org",
+        "user_id": "@bot:example.org",
+        "device_id": "ABCDEF",
+        "access_token": "s3kr1t",
+        "sync_token": "s72595_4483_1934"
+    }"#).unwrap();
+    let cli = MatrixClient::restore_session(&session).unwrap();
+    assert_eq!(cli.get_user_id(), "@bot:example.org");
+    assert_eq!(cli.device_id(), Some("ABCDEF"));
+    assert_eq!(cli.session(), Session { sync_token: None, ..session });
+}
+#[test]
+fn server_versions() {
+    let versions: VersionsReply = serde_json::from_str(r#"{
+        "versions": ["r0.6.1", "v1.1"],
+        "unstable_features": {"org.matrix.e2e_cross_signing": true, "org.example.off": false}
+    }"#).unwrap();
+    assert!(versions.supports("v1.1"));
+    assert!(!versions.supports("v1.2"));
+    assert!(versions.unstable_feature("org.matrix.e2e_cross_signing"));
+    assert!(!versions.unstable_feature("org.example.off"));
+    assert!(!versions.unstable_feature("org.example.missing"));
+    let session = Session {
+        homeserver_url: "https://example.org".into(),
+        user_id: "@bot:example.org".into(),
+        device_id: None,
+        access_token: "s3kr1t".into(),
+        sync_token: None
+    };
+    let cli = MatrixClient::restore_session(&session).unwrap();
+    assert_eq!(cli.server_versions(), None);
+    assert!(!cli.supports_version("v1.1"));
+    let cli = MatrixClientBuilder::new()
+        .server_versions(versions.clone())
+        .restore_session(&session)
+        .unwrap();
+    assert_eq!(cli.server_versions(), Some(versions));
+    assert!(cli.supports_version("r0.6.1"));
+    assert!(cli.supports_unstable_feature("org.matrix.e2e_cross_signing"));
+}
+#[test]
+fn api_version_negotiation() {
+    let mut versions: VersionsReply = serde_json::from_str(r#"{"versions": ["r0.5.0", "r0.6.1"]}"#).unwrap();
+    assert_eq!(ApiVersion::negotiate(&versions), ApiVersion::R0);
+    versions.versions.push("v1.2".into());
+    assert_eq!(ApiVersion::negotiate(&versions), ApiVersion::V3);
+
+    let mut cli = MockClient::new();
+    let req = MatrixRequest::new_basic(Method::GET, "/sync");
+    assert_eq!(req.make_request(&cli).unwrap().uri(), "https://example.org/_matrix/client/r0/sync");
+    cli.version = ApiVersion::V3;
+    assert_eq!(req.make_request(&cli).unwrap().uri(), "https://example.org/_matrix/client/v3/sync");
+    let req = MatrixRequest::new(Method::GET, "/sync", (), matrix_api::request::apis::r0::ClientApi);
+    assert_eq!(req.make_request(&cli).unwrap().uri(), "https://example.org/_matrix/client/r0/sync");
+}
+#[test]
+fn login_helpers() {
+    assert_eq!(serde_json::to_value(UserIdentifier::email("bot@example.org")).unwrap(), serde_json::json!({
+        "type": "m.id.thirdparty",
+        "medium": "email",
+        "address": "bot@example.org"
+    }));
+    let builder = MatrixClientBuilder::new();
+    assert_eq!(builder.sso_redirect_url("https://example.org", "http://127.0.0.1:1234/?a=b&c", Some("oidc-github")),
+               "https://example.org/_matrix/client/r0/login/sso/redirect/oidc-github?redirectUrl=http%3A%2F%2F127.0.0.1%3A1234%2F%3Fa%3Db%26c");
+    let versions: VersionsReply = serde_json::from_str(r#"{"versions": ["v1.1"]}"#).unwrap();
+    let builder = builder.server_versions(versions);
+    assert_eq!(builder.sso_redirect_url("https://example.org", "http://localhost/", None),
+               "https://example.org/_matrix/client/v3/login/sso/redirect?redirectUrl=http%3A%2F%2Flocalhost%2F");
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let token = rt.block_on(async {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = SsoCallbackListener::bind().await.unwrap();
+        let addr = listener.redirect_url()["http://".len()..].trim_end_matches('/').to_string();
+        let browser = tokio::spawn(async move {
+            for path in &["/favicon.ico", "/?loginToken=abc%2Bdef"] {
+                let mut sock = tokio::net::TcpStream::connect(&addr).await.unwrap();
+                sock.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+                let mut resp = String::new();
+                sock.read_to_string(&mut resp).await.unwrap();
+            }
+        });
+        let token = listener.wait_for_token().await.unwrap();
+        browser.await.unwrap();
+        token
+    });
+    assert_eq!(token, "abc+def");
+}
+#[test]
+fn uiaa_flow() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (401, r#"{"flows": [{"stages": ["m.login.recaptcha", "m.login.dummy"]}, {"stages": ["m.login.registration_token"]}],
+                  "params": {"m.login.recaptcha": {"public_key": "abc"}}, "session": "xyz"}"#),
+        (401, r#"{"flows": [{"stages": ["m.login.recaptcha", "m.login.dummy"]}, {"stages": ["m.login.registration_token"]}],
+                  "session": "xyz", "completed": ["m.login.recaptcha"]}"#),
+        (200, r#"{"user_id": "@bot:example.org", "access_token": "tok", "device_id": "DEV"}"#)
+    ]);
+    let req = MatrixRequest::new_with_body_ser(Method::POST, "/register", serde_json::json!({"password": "hunter2"}));
+    let mut req = UiaaRequest::new(req);
+    let info = match futures::executor::block_on(req.send::<_, serde_json::Value>(&cli, None)).unwrap() {
+        UiaaResponse::AuthRequired(info) => info,
+        UiaaResponse::Done(_) => panic!("expected auth to be required")
+    };
+    assert_eq!(info.next_stages(), vec!["m.login.recaptcha", "m.login.registration_token"]);
+    assert_eq!(info.params["m.login.recaptcha"]["public_key"], "abc");
+    match futures::executor::block_on(req.send::<_, serde_json::Value>(&cli, Some(AuthData::Recaptcha("resp".into())))).unwrap() {
+        UiaaResponse::AuthRequired(info) => assert_eq!(info.next_stages(), vec!["m.login.dummy"]),
+        UiaaResponse::Done(_) => panic!("expected auth to be required")
+    }
+    match futures::executor::block_on(req.send::<_, serde_json::Value>(&cli, Some(AuthData::Dummy))).unwrap() {
+        UiaaResponse::Done(rpl) => assert_eq!(rpl["device_id"], "DEV"),
+        UiaaResponse::AuthRequired(_) => panic!("expected registration to finish")
+    }
+    {
+        let sent = cli.sent.borrow();
+        assert!(sent[0].get("auth").is_none());
+        assert_eq!(sent[1]["auth"], serde_json::json!({"type": "m.login.recaptcha", "response": "resp", "session": "xyz"}));
+        assert_eq!(sent[2]["auth"], serde_json::json!({"type": "m.login.dummy", "session": "xyz"}));
+        assert_eq!(sent[2]["password"], "hunter2");
+    }
+
+    let err: MatrixResult<()> = futures::executor::block_on({
+        cli.replies.borrow_mut().push_back((401, r#"{"flows": [], "errcode": "M_FORBIDDEN"}"#));
+        MatrixRequest::new_basic(Method::POST, "/delete_devices").send(&cli)
+    });
+    assert!(err.unwrap_err().is_forbidden());
+}
+#[test]
+fn account_management() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "s"}"#),
+        (200, "{}"),
+        (200, r#"{"threepids": [{"medium": "email", "address": "bot@example.org", "validated_at": 1, "added_at": 2}]}"#)
+    ]);
+    let mut req = Account::change_password("hunter3", false);
+    assert!(matches!(futures::executor::block_on(req.discarding_send(&cli, None)).unwrap(),
+                     UiaaResponse::AuthRequired(_)));
+    let auth = AuthData::Password {
+        identifier: UserIdentifier::user("@bot:example.org"),
+        password: "hunter2".into()
+    };
+    assert!(matches!(futures::executor::block_on(req.discarding_send(&cli, Some(auth))).unwrap(),
+                     UiaaResponse::Done(())));
+    assert_eq!(cli.sent.borrow()[1], serde_json::json!({
+        "new_password": "hunter3",
+        "logout_devices": false,
+        "auth": {
+            "type": "m.login.password",
+            "identifier": {"type": "m.id.user", "user": "@bot:example.org"},
+            "password": "hunter2",
+            "session": "s"
+        }
+    }));
+    let threepids = futures::executor::block_on(Account::get_3pids(&cli)).unwrap();
+    assert_eq!(threepids.threepids[0].address, "bot@example.org");
+}
+#[test]
+fn device_management() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (200, r#"{"devices": [{"device_id": "OLD", "last_seen_ts": 1}, {"device_id": "NEW", "display_name": "bot"}]}"#),
+        (401, r#"{"flows": [{"stages": ["m.login.dummy"]}], "session": "s"}"#)
+    ]);
+    let devices = futures::executor::block_on(Devices::get_devices(&cli)).unwrap();
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[1].display_name.as_ref().map(|x| x as &str), Some("bot"));
+    let stale = devices.iter()
+        .filter(|d| d.display_name.is_none())
+        .map(|d| d.device_id.clone())
+        .collect::<Vec<_>>();
+    let mut req = Devices::delete_devices(&stale);
+    assert!(matches!(futures::executor::block_on(req.discarding_send(&cli, None)).unwrap(),
+                     UiaaResponse::AuthRequired(_)));
+    assert!(matches!(futures::executor::block_on(req.discarding_send(&cli, Some(AuthData::Dummy))).unwrap(),
+                     UiaaResponse::Done(())));
+    assert_eq!(cli.sent.borrow()[2], serde_json::json!({
+        "devices": ["OLD"],
+        "auth": {"type": "m.login.dummy", "session": "s"}
+    }));
+}
+#[test]
+fn sync_filters() {
+    let filter = Filter {
+        room: Some(RoomFilter {
+            rooms: Some(vec!["!room:example.org".into()]),
+            timeline: Some(RoomEventFilter {
+                types: Some(vec!["m.room.message".into()]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let json = serde_json::json!({"room": {"rooms": ["!room:example.org"], "timeline": {"types": ["m.room.message"]}}});
+    assert_eq!(serde_json::to_value(&filter).unwrap(), json);
+
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (200, r#"{"filter_id": "f1"}"#),
+        (200, r#"{"next_batch": "s1", "rooms": {}}"#),
+        (200, r#"{"next_batch": "s2", "rooms": {}}"#)
+    ]);
+    let id = futures::executor::block_on(Filters::upload(&cli, &filter)).unwrap();
+    assert_eq!(id, "f1");
+    assert_eq!(cli.uris.borrow()[0], "https://example.org/_matrix/client/r0/user/@bot:example.org/filter");
+    assert_eq!(cli.sent.borrow()[0], json);
+
+    let mut ss = SyncStream::new(cli.clone());
+    ss.set_filter(Some(id));
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    assert!(cli.uris.borrow()[1].contains("filter=f1"));
+    ss.set_filter(Some(filter));
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    assert!(cli.uris.borrow()[2].contains("filter=%7B%22room%22%3A%7B"));
+}
+#[test]
+fn resumable_sync() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (200, r#"{"next_batch": "s2", "rooms": {}}"#),
+        (200, r#"{"next_batch": "s3", "rooms": {}}"#)
+    ]);
+    let saved = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let saved2 = saved.clone();
+    let mut ss = SyncStream::new_from_token(cli.clone(), "s1".into());
+    ss.set_token_store(move |tok: &str| saved2.lock().unwrap().push(tok.to_string()));
+    ss.set_full_state(true);
+    assert_eq!(ss.next_batch(), Some("s1"));
+
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    assert!(cli.uris.borrow()[0].contains("since=s1"));
+    assert!(cli.uris.borrow()[0].contains("full_state=true"));
+    assert_eq!(ss.next_batch(), Some("s2"));
+    // The reply hasn't been processed yet, as far as the stream knows.
+    assert!(saved.lock().unwrap().is_empty());
+
+    ss.set_full_state(false);
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    assert!(!cli.uris.borrow()[1].contains("full_state"));
+    assert_eq!(*saved.lock().unwrap(), vec!["s2".to_string()]);
+    ss.save_token();
+    ss.save_token();
+    assert_eq!(*saved.lock().unwrap(), vec!["s2".to_string(), "s3".to_string()]);
+}
+#[test]
+fn sync_backoff() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (502, ""),
+        (429, r#"{"errcode": "M_LIMIT_EXCEEDED"}"#),
+        (200, r#"{"next_batch": "s2", "rooms": {}}"#),
+        (401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token"}"#)
+    ]);
+    let mut ss = SyncStream::new_from_token(cli.clone(), "s1".into());
+    ss.set_backoff(SyncBackoff {
+        base_delay: Duration::from_millis(1),
+        ..SyncBackoff::default()
+    });
+    let state = ss.connection_state();
+    assert_eq!(*state.borrow(), ConnectionState::Connecting);
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    let rpl = rt.block_on(ss.next()).unwrap().unwrap();
+    assert_eq!(rpl.next_batch, "s2");
+    assert_eq!(cli.uris.borrow().len(), 3);
+    assert!(cli.uris.borrow()[2].contains("since=s1"));
+    assert_eq!(*state.borrow(), ConnectionState::Connected);
+
+    let err = rt.block_on(ss.next()).unwrap().unwrap_err();
+    assert!(err.is_unknown_token());
+    assert!(!err.is_transient());
+    assert!(matches!(*state.borrow(), ConnectionState::Failed(_)));
+    assert_eq!(ss.next_batch(), Some("s2"));
+
+    cli.replies.borrow_mut().push_back((502, ""));
+    ss.set_backoff(SyncBackoff::never());
+    assert!(rt.block_on(ss.next()).unwrap().unwrap_err().is_transient());
+}
+#[test]
+fn request_timeouts() {
+    let mut req = MatrixRequest::new_basic(Method::GET, "/account/whoami");
+    assert!(req.make_request(&MockClient::new()).unwrap().extensions().get::<RequestTimeout>().is_none());
+    req.timeout = Some(Duration::from_millis(50));
+    assert_eq!(req.make_request(&MockClient::new()).unwrap().extensions().get::<RequestTimeout>(),
+               Some(&RequestTimeout(Duration::from_millis(50))));
+
+    // Initial syncs and uploads get longer deadlines than the default.
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().extend(vec![
+        (200, r#"{"next_batch": "s1"}"#),
+        (200, r#"{"next_batch": "s2"}"#),
+        (200, r#"{"content_uri": "mxc://example.org/abc"}"#)
+    ]);
+    let mut ss = SyncStream::new(cli.clone());
+    ss.set_backoff(SyncBackoff::never());
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    futures::executor::block_on(Media::upload(&cli, vec![0; 640 * 1024], "image/png")).unwrap();
+    assert_eq!(*cli.timeouts.borrow(), vec![
+        Some(Duration::from_secs(600)),
+        Some(Duration::from_secs(60)),
+        Some(Duration::from_secs(70))
+    ]);
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let err = rt.block_on(async move {
+        // A "homeserver" that accepts connections, but never says anything.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut socks = vec![];
+            loop {
+                socks.push(listener.accept().await.unwrap());
+            }
+        });
+        let session = Session {
+            homeserver_url: url,
+            user_id: "@bot:example.org".into(),
+            device_id: None,
+            access_token: "s3kr1t".into(),
+            sync_token: None
+        };
+        let cli = MatrixClientBuilder::new()
+            .retry_policy(RetryPolicy::never())
+            .request_timeout(Some(Duration::from_secs(3600)))
+            .restore_session(&session)
+            .unwrap();
+        req.send::<_, serde_json::Value>(&cli).await.unwrap_err()
+    });
+    assert!(matches!(err, MatrixError::Timeout));
+    assert!(err.is_transient());
+}
+#[test]
+fn event_dispatch() {
+    let sync: SyncReply = serde_json::from_str(&read_file("tests/sync_v3.json")).unwrap();
+    let session = Session {
+        homeserver_url: "https://example.org".into(),
+        user_id: "@bot:example.org".into(),
+        device_id: None,
+        access_token: "s3kr1t".into(),
+        sync_token: None
+    };
+    let cli = MatrixClient::restore_session(&session).unwrap();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let errors = Arc::new(Mutex::new(vec![]));
+    let mut dispatcher = Dispatcher::new(cli);
+    let seen2 = seen.clone();
+    let errors2 = errors.clone();
+    dispatcher
+        .on_message(move |ctx, evt| {
+            let seen = seen2.clone();
+            async move {
+                seen.lock().unwrap().push((ctx.room.unwrap().id.to_string(), evt.event_type));
+                Ok(())
+            }
+        })
+        .on_event_type("m.tag", |_, _| async { Err(MatrixError::Timeout) })
+        .on(|evt| evt.source == EventSource::KnockState, |_, _| async { panic!("knock knock") })
+        .on_error(move |e| errors2.lock().unwrap().push(e));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(dispatcher.run(futures::stream::iter(vec![Ok(sync)]))).unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![("!726s6s6q:example.com".to_string(), "m.room.message".to_string())]);
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors.iter().any(|e| matches!(e, HandlerError::Failed(MatrixError::Timeout))));
+    assert!(errors.iter().any(|e| matches!(e, HandlerError::Panicked(m) if m == "knock knock")));
+}
+#[test]
+fn room_state_tracking() {
+    let states = RoomStates::new();
+    let sync: SyncReply = serde_json::from_str(&read_file("tests/sync_unstable.json")).unwrap();
+    states.apply_sync(&sync);
+    let room = Room::from_id("!726s6s6q:example.com");
+    let mut joined = states.with_room(&room, |s| s.joined_members().map(|(id, _)| id.to_string()).collect::<Vec<_>>()).unwrap();
+    joined.sort();
+    assert_eq!(joined, vec!["@alice:example.com", "@bob:example.com"]);
+    assert_eq!(states.with_room(&Room::from_id("!696r7674:example.com"), |s| s.name().map(|x| x.to_string())),
+               Some(Some("My Room Name".to_string())));
+
+    let sync: SyncReply = serde_json::from_str(r#"{
+        "next_batch": "s2",
+        "rooms": {"join": {"!726s6s6q:example.com": {
+            "timeline": {"events": [
+                {"type": "m.room.topic", "state_key": "", "content": {"topic": "Fish"}, "sender": "@alice:example.com", "event_id": "$1", "origin_server_ts": 1},
+                {"type": "m.room.join_rules", "state_key": "", "content": {"join_rule": "invite"}, "sender": "@alice:example.com", "event_id": "$2", "origin_server_ts": 2},
+                {"type": "m.room.power_levels", "state_key": "", "content": {"users": {"@alice:example.com": 100}}, "sender": "@alice:example.com", "event_id": "$3", "origin_server_ts": 3},
+                {"type": "m.room.member", "state_key": "@bob:example.com", "content": {"membership": "leave"}, "sender": "@bob:example.com", "event_id": "$4", "origin_server_ts": 4}
+            ]}
+        }}}
+    }"#).unwrap();
+    states.apply_sync(&sync);
+    let state = states.get(&room).unwrap();
+    assert_eq!(state.topic(), Some("Fish"));
+    assert_eq!(state.name(), None);
+    assert_eq!(state.join_rule(), Some(&JoinRule::Invite));
+    assert_eq!(state.user_power_level("@alice:example.com"), 100);
+    assert_eq!(state.user_power_level("@bob:example.com"), 0);
+    assert_eq!(state.member("@bob:example.com").unwrap().membership, Membership::Leave);
+    assert_eq!(state.joined_members().count(), 1);
+    assert_eq!(state.members().count(), 2);
+    assert_eq!(states.rooms().len(), 2);
+}
+const STATE_STORE_SYNC: &str = r#"{
+    "next_batch": "s2",
+    "account_data": {"events": [{"type": "m.direct", "content": {"@bob:example.com": ["!726s6s6q:example.com"]}}]},
+    "rooms": {"join": {
+        "!726s6s6q:example.com": {
+            "state": {"events": [
+                {"type": "m.room.name", "state_key": "", "content": {"name": "Fish"}, "sender": "@alice:example.com", "event_id": "$1", "origin_server_ts": 1}
+            ]},
+            "timeline": {"events": [
+                {"type": "m.room.member", "state_key": "@bot:example.org", "content": {"membership": "join", "displayname": "Bot"}, "sender": "@bot:example.org", "event_id": "$2", "origin_server_ts": 2},
+                {"type": "m.room.message", "content": {"msgtype": "m.text", "body": "hi"}, "sender": "@bot:example.org", "event_id": "$3", "origin_server_ts": 3, "unsigned": {"age": 1, "transaction_id": "txn1"}}
+            ]},
+            "account_data": {"events": [{"type": "m.tag", "content": {"tags": {"u.work": {}}}}]},
+            "summary": {"m.joined_member_count": 2}
+        },
+        "!summary:example.com": {
+            "timeline": {"events": []},
+            "summary": {"m.heroes": ["@bob:example.com"], "m.joined_member_count": 2}
+        }
+    }}
+}"#;
+fn pending_transaction(txn_id: &str, body: &str) -> PendingTransaction {
+    PendingTransaction {
+        room: Room::from_id("!726s6s6q:example.com"),
+        txn_id: txn_id.to_string(),
+        event_type: "m.room.message".into(),
+        content: serde_json::json!({"msgtype": "m.text", "body": body})
+    }
+}
+/// Save `STATE_STORE_SYNC` (after adding some pending transactions) to a store.
+fn fill_state_store(store: Arc<dyn StateStore>) -> MockClient {
+    for txn_id in &["txn1", "txn2", "txn3"] {
+        store.add_pending_transaction(&pending_transaction(txn_id, "hi")).unwrap();
+    }
+    // Replacing a transaction mustn't change its place in the queue.
+    store.add_pending_transaction(&pending_transaction("txn2", "hello")).unwrap();
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((200, STATE_STORE_SYNC));
+    let mut ss = SyncStream::new(cli.clone());
+    ss.set_state_store(Some(store)).unwrap();
+    futures::executor::block_on(ss.next()).unwrap().unwrap();
+    cli
+}
+fn check_state_store(store: Arc<dyn StateStore>) {
+    let room = Room::from_id("!726s6s6q:example.com");
+    let cli = fill_state_store(store.clone());
+    assert_eq!(store.sync_token().unwrap(), Some("s2".to_string()));
+    let mut rooms = store.rooms().unwrap();
+    rooms.sort();
+    assert_eq!(rooms, vec![room.clone(), Room::from_id("!summary:example.com")]);
+    let state = store.room_state(&room).unwrap().unwrap();
+    assert_eq!(state.name(), Some("Fish"));
+    assert_eq!(state.summary().joined_member_count, Some(2));
+    let summary_only = store.room_state(&Room::from_id("!summary:example.com")).unwrap().unwrap();
+    assert!(summary_only.is_empty());
+    assert_eq!(summary_only.display_name("@bot:example.org"), "@bob:example.com");
+    assert_eq!(store.member(&room, "@bot:example.org").unwrap().unwrap().displayname, Some("Bot".to_string()));
+    assert_eq!(store.members(&room).unwrap().len(), 1);
+    assert!(store.member(&room, "@bob:example.com").unwrap().is_none());
+    assert!(store.account_data("m.direct").unwrap().is_some());
+    assert!(store.account_data("m.tag").unwrap().is_none());
+    assert!(store.room_account_data(&room, "m.tag").unwrap().is_some());
+    let pending = store.pending_transactions().unwrap();
+    assert_eq!(pending, vec![pending_transaction("txn2", "hello"), pending_transaction("txn3", "hi")]);
+    cli.replies.borrow_mut().push_back((200, r#"{"event_id": "$4"}"#));
+    assert_eq!(futures::executor::block_on(pending[0].send(&cli)).unwrap().event_id, "$4");
+    assert!(cli.uris.borrow()[1].ends_with("/rooms/!726s6s6q:example.com/send/m.room.message/txn2"));
+    store.remove_pending_transaction("txn2").unwrap();
+    store.remove_pending_transaction("txn3").unwrap();
+    assert!(store.pending_transactions().unwrap().is_empty());
+
+    // Messages sent with `send_stored` are kept if they might not have got
+    // there, and forgotten otherwise.
+    cli.replies.borrow_mut().push_back((502, ""));
+    let res = futures::executor::block_on(room.cli(&cli).send_stored(store.clone(), Message::Text {
+        body: "hi".into(),
+        formatted_body: None,
+        format: None,
+        relates_to: None,
+        new_content: None
+    }));
+    assert!(res.unwrap_err().is_transient());
+    let pending = store.pending_transactions().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].txn_id, "0");
+    assert_eq!(pending[0].content["body"], "hi");
+    cli.replies.borrow_mut().push_back((200, r#"{"event_id": "$5"}"#));
+    let reply = futures::executor::block_on(pending[0].clone().send_stored(&cli, store.clone())).unwrap();
+    assert_eq!(reply.event_id, "$5");
+    assert!(store.pending_transactions().unwrap().is_empty());
+
+    let mut ss = SyncStream::new(cli.clone());
+    ss.set_state_store(Some(store)).unwrap();
+    assert_eq!(ss.next_batch(), Some("s2"));
+}
+#[test]
+fn memory_state_store() {
+    check_state_store(Arc::new(MemoryStore::new()));
+}
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_state_store() {
+    check_state_store(Arc::new(matrix_api::store::SqliteStore::open_in_memory().unwrap()));
+}
+#[cfg(feature = "sqlite")]
+#[test]
+fn state_stores_agree() {
+    let memory: Arc<dyn StateStore> = Arc::new(MemoryStore::new());
+    let sqlite: Arc<dyn StateStore> = Arc::new(matrix_api::store::SqliteStore::open_in_memory().unwrap());
+    fill_state_store(memory.clone());
+    fill_state_store(sqlite.clone());
+    let mut rooms = memory.rooms().unwrap();
+    rooms.sort();
+    let mut sqlite_rooms = sqlite.rooms().unwrap();
+    sqlite_rooms.sort();
+    assert_eq!(rooms, sqlite_rooms);
+    for room in rooms.iter() {
+        let (a, b) = (memory.room_state(room).unwrap().unwrap(), sqlite.room_state(room).unwrap().unwrap());
+        assert_eq!(a.summary(), b.summary());
+        assert_eq!(a.len(), b.len());
+        assert_eq!(a.display_name("@bot:example.org"), b.display_name("@bot:example.org"));
+        assert_eq!(memory.members(room).unwrap().len(), sqlite.members(room).unwrap().len());
+    }
+    assert_eq!(memory.sync_token().unwrap(), sqlite.sync_token().unwrap());
+    assert_eq!(memory.pending_transactions().unwrap(), sqlite.pending_transactions().unwrap());
+}
+fn member_event(user_id: &str, membership: &str, displayname: Option<&str>) -> Event {
+    serde_json::from_value(serde_json::json!({
+        "type": "m.room.member",
+        "state_key": user_id,
+        "content": {"membership": membership, "displayname": displayname},
+        "sender": user_id,
+        "event_id": format!("$member-{}", user_id),
+        "origin_server_ts": 1
+    })).unwrap()
+}
+#[test]
+fn room_display_names() {
+    let me = "@me:example.org";
+    let mut state = RoomState::new();
+    assert_eq!(state.display_name(me), "Empty room");
+    state.apply(&member_event(me, "join", Some("Me")));
+    state.apply(&member_event("@bob:example.org", "join", Some("Bob")));
+    state.apply(&member_event("@carol:example.org", "invite", None));
+    state.apply(&member_event("@dave:example.org", "join", Some("Bob")));
+    state.apply(&member_event("@eve:example.org", "leave", Some("Eve")));
+    assert_eq!(state.member_display_name(me), "Me");
+    assert_eq!(state.member_display_name("@bob:example.org"), "Bob (@bob:example.org)");
+    assert_eq!(state.member_display_name("@carol:example.org"), "@carol:example.org");
+    assert_eq!(state.member_display_name("@nobody:example.org"), "@nobody:example.org");
+    assert_eq!(state.display_name(me), "Bob (@bob:example.org), @carol:example.org and Bob (@dave:example.org)");
+
+    // Someone who's left doesn't count when disambiguating.
+    state.apply(&member_event("@dave:example.org", "leave", Some("Bob")));
+    assert_eq!(state.member_display_name("@bob:example.org"), "Bob");
+    assert_eq!(state.display_name(me), "Bob and @carol:example.org");
+
+    state.apply_summary(&serde_json::from_str(r#"{"m.heroes": ["@bob:example.org"], "m.joined_member_count": 5}"#).unwrap());
+    state.apply_summary(&serde_json::from_str(r#"{"m.invited_member_count": 1}"#).unwrap());
+    assert_eq!(state.summary().heroes, Some(vec!["@bob:example.org".to_string()]));
+    assert_eq!(state.display_name(me), "Bob and 4 others");
+    state.apply_summary(&serde_json::from_str(r#"{"m.joined_member_count": 2, "m.invited_member_count": 0}"#).unwrap());
+    assert_eq!(state.display_name(me), "Bob");
+    state.apply_summary(&serde_json::from_str(r#"{"m.joined_member_count": 1, "m.heroes": ["@eve:example.org"]}"#).unwrap());
+    assert_eq!(state.display_name(me), "Empty room (was Eve)");
+
+    state.apply(&serde_json::from_value(serde_json::json!({
+        "type": "m.room.canonical_alias", "state_key": "", "content": {"alias": "#fish:example.org"},
+        "sender": me, "event_id": "$alias", "origin_server_ts": 2
+    })).unwrap());
+    assert_eq!(state.display_name(me), "#fish:example.org");
+    state.apply(&serde_json::from_value(serde_json::json!({
+        "type": "m.room.name", "state_key": "", "content": {"name": "Fish"},
+        "sender": me, "event_id": "$name", "origin_server_ts": 3
+    })).unwrap());
+    assert_eq!(state.display_name(me), "Fish");
+}
+#[test]
+fn message_replies() {
+    let original: Event = serde_json::from_value(serde_json::json!({
+        "type": "m.room.message",
+        "content": {"msgtype": "m.text", "body": "Is it <b>fish</b>?\nOr not?"},
+        "sender": "@alice:example.org",
+        "event_id": "$original",
+        "origin_server_ts": 1
+    })).unwrap();
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((200, r#"{"event_id": "$reply"}"#));
+    let room = Room::from_id("!room:example.org");
+    let reply = Message::Text {
+        body: "It's fish".into(),
+        formatted_body: None,
+        format: None,
+        relates_to: None,
+        new_content: None
+    };
+    futures::executor::block_on(room.cli(&cli).reply(&original, reply)).unwrap();
+    let sent = cli.sent.borrow()[0].clone();
+    assert_eq!(sent["body"], "> <@alice:example.org> Is it <b>fish</b>?\n> Or not?\n\nIt's fish");
+    assert_eq!(sent["format"], "org.matrix.custom.html");
+    assert_eq!(sent["formatted_body"], "<mx-reply><blockquote><a href=\"https://matrix.to/#/%21room%3Aexample.org/%24original\">In reply to</a> \
+                                        <a href=\"https://matrix.to/#/%40alice%3Aexample.org\">@alice:example.org</a><br />\
+                                        Is it &lt;b&gt;fish&lt;/b&gt;?<br />Or not?</blockquote></mx-reply>It&#39;s fish");
+    assert_eq!(sent["m.relates_to"], serde_json::json!({"m.in_reply_to": {"event_id": "$original"}}));
+
+    // Receiving the reply, and replying to it in turn.
+    let mut msg: Message = serde_json::from_value(sent).unwrap();
+    assert_eq!(msg.in_reply_to(), Some("$original"));
+    let mut stripped = msg.clone();
+    stripped.strip_reply_fallback();
+    assert_eq!(stripped.body(), "It's fish");
+    assert_eq!(stripped.html_body(), Some("It&#39;s fish"));
+    let mut reply = Message::Emote {
+        body: "agrees".into(),
+        relates_to: None,
+        new_content: None
+    };
+    reply.make_reply("!room:example.org", "$reply", "@bob:example.org", &msg);
+    assert_eq!(reply.body(), "> <@bob:example.org> It's fish\n\nagrees");
+    assert_eq!(reply.in_reply_to(), Some("$reply"));
+
+    // The IDs can't break out of the HTML fallback.
+    let mut reply = Message::Text {
+        body: "hi".into(),
+        formatted_body: None,
+        format: None,
+        relates_to: None,
+        new_content: None
+    };
+    reply.make_reply("!room:example.org", "$a\"b", "@<b>\"evil\":example.org", &stripped);
+    assert_eq!(reply.html_body(), Some("<mx-reply><blockquote><a href=\"https://matrix.to/#/%21room%3Aexample.org/%24a%22b\">In reply to</a> \
+                                        <a href=\"https://matrix.to/#/%40%3Cb%3E%22evil%22%3Aexample.org\">@&lt;b&gt;&quot;evil&quot;:example.org</a><br />\
+                                        It&#39;s fish</blockquote></mx-reply>hi"));
+
+    // Messages that aren't replies are left alone, even if they look like one.
+    msg.set_relates_to(None);
+    let before = msg.body().to_string();
+    msg.strip_reply_fallback();
+    assert_eq!(msg.body(), before);
+}
+#[test]
+fn message_edits() {
+    let cli = MockClient::new();
+    cli.replies.borrow_mut().push_back((200, r#"{"event_id": "$edit"}"#));
+    let room = Room::from_id("!room:example.org");
+    let new = Message::Notice {
+        body: "Status: done".into(),
+        formatted_body: Some("Status: <b>done</b>".into()),
+        format: Some("org.matrix.custom.html".into()),
+        relates_to: None,
+        new_content: None
+    };
+    futures::executor::block_on(room.cli(&cli).edit("$status", new)).unwrap();
+    assert!(cli.uris.borrow()[0].contains("/rooms/!room:example.org/send/m.room.message/"));
+    let sent = cli.sent.borrow()[0].clone();
+    assert_eq!(sent, serde_json::json!({
+        "msgtype": "m.notice",
+        "body": "* Status: done",
+        "formatted_body": "* Status: <b>done</b>",
+        "format": "org.matrix.custom.html",
+        "m.new_content": {
+            "msgtype": "m.notice",
+            "body": "Status: done",
+            "formatted_body": "Status: <b>done</b>",
+            "format": "org.matrix.custom.html"
+        },
+        "m.relates_to": {"rel_type": "m.replace", "event_id": "$status"}
+    }));
+
+    let event = |id: &str, sender: &str, ts: u64, content: serde_json::Value| -> Event {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "content": content,
+            "sender": sender,
+            "event_id": id,
+            "origin_server_ts": ts
+        })).unwrap()
+    };
+    let edit = |body: &str| {
+        let new = Message::Notice {
+            body: body.into(),
+            formatted_body: None,
+            format: None,
+            relates_to: None,
+            new_content: None
+        };
+        serde_json::to_value(Message::edit_of("$status", new)).unwrap()
+    };
+    let mut original = event("$status", "@bot:example.org", 1, serde_json::json!({
+        "msgtype": "m.notice",
+        "body": "Status: working",
+        "m.relates_to": {"m.in_reply_to": {"event_id": "$request"}}
+    }));
+    let edits = vec![
+        event("$edit1", "@bot:example.org", 3, edit("Status: done")),
+        event("$edit2", "@bot:example.org", 2, edit("Status: nearly done")),
+        event("$edit3", "@mallory:example.org", 4, edit("Status: pwned")),
+        event("$edit4", "@bot:example.org", 5, sent)
+    ];
+    assert_eq!(edits[0].replaces(), Some("$status"));
+    assert_eq!(original.replaces(), None);
+    let mut edited_edit = edits[0].clone();
+    assert!(!edited_edit.apply_latest_edit(&edits));
+    assert!(original.apply_latest_edit(&edits[..3]));
+    match original.content {
+        Content::RoomMessage(ref m) => {
+            assert_eq!(m.body(), "Status: done");
+            assert_eq!(m.in_reply_to(), Some("$request"));
+            assert!(m.new_content().is_none());
+        },
+        ref x => panic!("wrong content: {:?}", x)
+    }
+    assert!(original.apply_latest_edit(&edits));
+    match original.content {
+        Content::RoomMessage(ref m) => assert_eq!(m.html_body(), Some("Status: <b>done</b>")),
+        ref x => panic!("wrong content: {:?}", x)
+    }
+}
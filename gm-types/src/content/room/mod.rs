@@ -81,6 +81,7 @@ pub struct JoinRules {
 /// otherwise.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Member {
+    #[allow(clippy::tabs_in_doc_comments)]
     ///	The avatar URL for this user, if any. This is added by the homeserver.
     #[serde(default)]
     pub avatar_url: Option<String>,
@@ -169,6 +170,7 @@ pub struct PowerLevels {
 /// key.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Redaction {
+    #[allow(clippy::tabs_in_doc_comments)]
     ///	The reason for the redaction, if any.
     #[serde(default)]
     pub reason: Option<String>
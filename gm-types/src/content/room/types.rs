@@ -1,4 +1,4 @@
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all="snake_case")]
 /// Defines who can join a room
 pub enum JoinRule {
@@ -8,7 +8,7 @@ pub enum JoinRule {
     // Knock,
     // Private,
 }
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all="snake_case")]
 /// Possible membership states of a user
 pub enum Membership {
@@ -28,6 +28,7 @@ pub enum Membership {
     // reserved word
     // Knock,
 }
+#[allow(clippy::derivable_impls)]
 impl Default for Membership {
     fn default() -> Self {
         Membership::Join
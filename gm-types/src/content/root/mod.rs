@@ -27,6 +27,7 @@ pub struct Presence {
     /// The current display name for this user, if any.
     #[serde(default)]
     pub displayname: Option<String>,
+    #[allow(clippy::tabs_in_doc_comments)]
     ///	The last time since this used performed some action, in milliseconds.
     #[serde(default)]
     pub last_active_ago: Option<u64>,
@@ -91,6 +91,57 @@ impl<'de> Deserialize<'de> for Event {
         })
     }
 }
+impl Event {
+    /// If this event is an edit of a message, the ID of the message it edits.
+    pub fn replaces(&self) -> Option<&str> {
+        match self.content {
+            Content::RoomMessage(ref m) => m.replaces(),
+            _ => None
+        }
+    }
+    /// Apply the latest valid edit of this message among `edits` (which can
+    /// contain any events), replacing this event's content with the edited
+    /// version. Returns whether an edit was applied.
+    ///
+    /// Edits only count if they were sent by the same user as this message,
+    /// and have new content. The latest is the one with the latest
+    /// `origin_server_ts` (or the greatest event ID, if two are the same). The
+    /// original message's relation (e.g. the message it was a reply to) is
+    /// kept, as edits can't change it.
+    pub fn apply_latest_edit<'a, I>(&mut self, edits: I) -> bool where I: IntoIterator<Item = &'a Event> {
+        let rd = match self.room_data {
+            Some(ref rd) => rd,
+            None => return false
+        };
+        let rel = match self.content {
+            // Edits of edits are ignored.
+            Content::RoomMessage(ref m) if m.replaces().is_none() => m.relates_to().cloned(),
+            _ => return false
+        };
+        let latest = edits.into_iter()
+            .filter_map(|e| {
+                let erd = e.room_data.as_ref()?;
+                let new = match e.content {
+                    Content::RoomMessage(ref m) => m.new_content()?,
+                    _ => return None
+                };
+                if e.replaces() != Some(&rd.event_id) || erd.sender != rd.sender {
+                    return None;
+                }
+                Some(((erd.origin_server_ts, &erd.event_id), new))
+            })
+            .max_by(|a, b| a.0.cmp(&b.0));
+        match latest {
+            Some((_, new)) => {
+                let mut new = new.clone();
+                new.set_relates_to(rel);
+                self.content = Content::RoomMessage(new);
+                true
+            },
+            None => false
+        }
+    }
+}
 /// Events in a room.
 #[derive(Deserialize, Default, Debug)]
 pub struct Events {
@@ -0,0 +1,120 @@
+//! Filters, for cutting down what `/sync` (and friends) send back.
+//!
+//! All of the fields here are optional, and default to not filtering anything;
+//! build filters with struct update syntax, e.g.
+//! `RoomEventFilter { types: Some(vec!["m.room.message".into()]), ..Default::default() }`.
+
+/// The format events are returned in.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventFormat {
+    /// The format used by the client-server API (the default).
+    #[serde(rename = "client")]
+    Client,
+    /// The raw event, as received over federation.
+    #[serde(rename = "federation")]
+    Federation
+}
+/// Filters events that aren't in any room (e.g. presence).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    /// The maximum number of events to return.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    /// Senders to exclude. Takes precedence over `senders`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<String>>,
+    /// Event types to exclude. Takes precedence over `types`; `*` wildcards
+    /// are allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    /// Senders to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    /// Event types to include; `*` wildcards are allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>
+}
+/// Filters events in rooms.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomEventFilter {
+    /// The maximum number of events to return.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    /// Senders to exclude. Takes precedence over `senders`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<String>>,
+    /// Event types to exclude. Takes precedence over `types`; `*` wildcards
+    /// are allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    /// Senders to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    /// Event types to include; `*` wildcards are allowed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    /// Room IDs to exclude. Takes precedence over `rooms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    /// Room IDs to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    /// If set, only include events that have (or, if false, don't have) a
+    /// `url` key in their content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains_url: Option<bool>,
+    /// Whether to only send the membership events needed to display the
+    /// senders of the returned events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lazy_load_members: Option<bool>,
+    /// With `lazy_load_members`, whether to send membership events the client
+    /// has already been sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_redundant_members: Option<bool>
+}
+/// Filters what's sent about rooms.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomFilter {
+    /// Room IDs to exclude. Takes precedence over `rooms`, and applies to all
+    /// the filters below as well.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    /// Room IDs to include, which applies to all the filters below as well.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    /// Filters ephemeral events (e.g. typing notifications).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<RoomEventFilter>,
+    /// Whether to include rooms the user has left.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_leave: Option<bool>,
+    /// Filters state events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<RoomEventFilter>,
+    /// Filters timeline events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<RoomEventFilter>,
+    /// Filters per-room account data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<RoomEventFilter>
+}
+/// A filter, as uploaded to `/user/{userId}/filter` or passed to `/sync`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Event fields to include (e.g. `content.body`); if set, all others are
+    /// left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
+    /// The format to return events in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<EventFormat>,
+    /// Filters presence updates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<EventFilter>,
+    /// Filters global account data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<EventFilter>,
+    /// Filters everything to do with rooms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>
+}
@@ -15,3 +15,4 @@ pub mod content;
 pub mod events;
 pub mod replies;
 pub mod sync;
+pub mod filter;
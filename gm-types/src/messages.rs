@@ -24,6 +24,7 @@ pub struct FileInfo {
 /// Information about an audio clip.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AudioInfo {
+    #[allow(clippy::tabs_in_doc_comments)]
     ///	The duration of the audio in milliseconds.
     pub duration: u32,
     /// The mimetype of the audio e.g. `audio/aac`.
@@ -63,7 +64,13 @@ pub enum Message {
         formatted_body: Option<String>,
         /// The format of the formatted body (if the message is formatted).
         #[serde(default)]
-        format: Option<String>
+        format: Option<String>,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.notice")]
     /// A m.notice message should be considered similar to a plain m.text message except
@@ -81,7 +88,13 @@ pub enum Message {
         formatted_body: Option<String>,
         /// The format of the formatted body (if the message is formatted).
         #[serde(default)]
-        format: Option<String>
+        format: Option<String>,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.image")]
     /// This message represents a single image and an optional thumbnail.
@@ -99,6 +112,12 @@ pub enum Message {
         thumbnail_url: Option<String>,
         /// Metadata about the image referred to in thumbnail_url.
         thumbnail_info: Option<ImageInfo>,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.emote")]
     /// This message is similar to m.text except that the sender is 'performing'
@@ -109,6 +128,12 @@ pub enum Message {
     Emote{
         /// The emote action to perform.
         body: String,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.file")]
     /// This message represents a generic file.
@@ -125,7 +150,13 @@ pub enum Message {
         /// The URL to the thumbnail of the file.
         thumbnail_url: Option<String>,
         /// The URL to the file.
-        url: String
+        url: String,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.location")]
     /// This message represents a real-world location.
@@ -135,7 +166,13 @@ pub enum Message {
         /// attachment'.
         body: String,
         /// A geo URI representing this location.
-        geo_uri: String
+        geo_uri: String,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.audio")]
     /// This message represents a single audio clip.
@@ -147,7 +184,13 @@ pub enum Message {
         /// The URL to the audio clip.
         url: String,
         /// Metadata for the audio clip referred to in url.
-        info: Option<AudioInfo>
+        info: Option<AudioInfo>,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.video")]
     /// This message represents a single video clip.
@@ -158,6 +201,274 @@ pub enum Message {
         /// The URL to the video clip.
         url: String,
         /// Metadata about the video clip referred to in url.
-        info: Option<VideoInfo>
+        info: Option<VideoInfo>,
+        /// What this message relates to (e.g. the message it's a reply to).
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// If this message is an edit, the new content of the edited message.
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
+    }
+}
+
+/// The relationship of an event to another one (the `m.relates_to` key).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RelatesTo {
+    /// The event this one is a reply to, if it's a reply.
+    #[serde(rename = "m.in_reply_to", default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<InReplyTo>,
+    /// The type of relationship (e.g. `m.replace`), for relationships other
+    /// than replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rel_type: Option<String>,
+    /// The event this one relates to, for relationships other than replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>
+}
+/// The event a message is a reply to (the `m.in_reply_to` key).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InReplyTo {
+    /// The ID of the event being replied to.
+    pub event_id: String
+}
+/// The `format` of HTML-formatted message bodies.
+pub const HTML_FORMAT: &str = "org.matrix.custom.html";
+/// The `rel_type` of edits.
+pub const REPLACE_REL_TYPE: &str = "m.replace";
+
+/// Escape text for including in HTML.
+fn escape_html(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            '\n' => ret.push_str("<br />"),
+            c => ret.push(c)
+        }
+    }
+    ret
+}
+/// Percent-encode a room ID, event ID or user ID, for use in a `matrix.to`
+/// link (which makes it safe to put in HTML, too).
+fn matrix_to_segment(id: &str) -> String {
+    let mut ret = String::with_capacity(id.len());
+    for b in id.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => ret.push(b as char),
+            b => ret.push_str(&format!("%{:02X}", b))
+        }
+    }
+    ret
+}
+impl Message {
+    /// The message's (plain text) body.
+    pub fn body(&self) -> &str {
+        use self::Message::*;
+        match *self {
+            Text { ref body, .. } | Notice { ref body, .. } | Image { ref body, .. } |
+                Emote { ref body, .. } | File { ref body, .. } | Location { ref body, .. } |
+                Audio { ref body, .. } | Video { ref body, .. } => body
+        }
+    }
+    /// The message's HTML body, if it has one.
+    pub fn html_body(&self) -> Option<&str> {
+        match *self {
+            Message::Text { formatted_body: Some(ref fb), format: Some(ref f), .. } |
+                Message::Notice { formatted_body: Some(ref fb), format: Some(ref f), .. }
+                if f == HTML_FORMAT => Some(fb),
+            _ => None
+        }
+    }
+    /// What this message relates to, if anything.
+    pub fn relates_to(&self) -> Option<&RelatesTo> {
+        self.relates_to_ref().as_ref()
+    }
+    /// Set what this message relates to.
+    pub fn set_relates_to(&mut self, rel: Option<RelatesTo>) {
+        *self.relates_to_mut() = rel;
+    }
+    /// The ID of the event this message is a reply to, if it's a reply.
+    pub fn in_reply_to(&self) -> Option<&str> {
+        self.relates_to()?.in_reply_to.as_ref().map(|r| &r.event_id as &str)
+    }
+    /// The ID of the event this message is an edit of, if it's an edit.
+    pub fn replaces(&self) -> Option<&str> {
+        let rel = self.relates_to()?;
+        if rel.rel_type.as_ref().map(|x| x as &str) != Some(REPLACE_REL_TYPE) {
+            return None;
+        }
+        rel.event_id.as_ref().map(|x| x as &str)
+    }
+    /// If this message is an edit, the new content of the edited message.
+    pub fn new_content(&self) -> Option<&Message> {
+        use self::Message::*;
+        match *self {
+            Text { ref new_content, .. } | Notice { ref new_content, .. } | Image { ref new_content, .. } |
+                Emote { ref new_content, .. } | File { ref new_content, .. } | Location { ref new_content, .. } |
+                Audio { ref new_content, .. } | Video { ref new_content, .. } => new_content.as_ref().map(|x| &**x)
+        }
+    }
+    /// Make an edit of the message with ID `event_id`, changing it to `new`.
+    ///
+    /// The edit has `new` as its `m.new_content`, and a copy of it with the
+    /// bodies prefixed by `* ` as its own content, for clients that don't
+    /// understand edits. Any relation `new` has is dropped, since edits can't
+    /// change what a message relates to.
+    pub fn edit_of(event_id: &str, mut new: Message) -> Message {
+        new.set_relates_to(None);
+        *new.new_content_mut() = None;
+        let mut ret = new.clone();
+        {
+            use self::Message::*;
+            match ret {
+                Text { ref mut body, ref mut formatted_body, .. } |
+                    Notice { ref mut body, ref mut formatted_body, .. } => {
+                    *body = format!("* {}", body);
+                    if let Some(ref mut fb) = *formatted_body {
+                        *fb = format!("* {}", fb);
+                    }
+                },
+                Image { ref mut body, .. } | Emote { ref mut body, .. } | File { ref mut body, .. } |
+                    Location { ref mut body, .. } | Audio { ref mut body, .. } | Video { ref mut body, .. } => {
+                    *body = format!("* {}", body);
+                }
+            }
+        }
+        ret.set_relates_to(Some(RelatesTo {
+            in_reply_to: None,
+            rel_type: Some(REPLACE_REL_TYPE.into()),
+            event_id: Some(event_id.into())
+        }));
+        *ret.new_content_mut() = Some(Box::new(new));
+        ret
+    }
+    fn new_content_mut(&mut self) -> &mut Option<Box<Message>> {
+        use self::Message::*;
+        match *self {
+            Text { ref mut new_content, .. } | Notice { ref mut new_content, .. } | Image { ref mut new_content, .. } |
+                Emote { ref mut new_content, .. } | File { ref mut new_content, .. } | Location { ref mut new_content, .. } |
+                Audio { ref mut new_content, .. } | Video { ref mut new_content, .. } => new_content
+        }
+    }
+    fn relates_to_ref(&self) -> &Option<RelatesTo> {
+        use self::Message::*;
+        match *self {
+            Text { ref relates_to, .. } | Notice { ref relates_to, .. } | Image { ref relates_to, .. } |
+                Emote { ref relates_to, .. } | File { ref relates_to, .. } | Location { ref relates_to, .. } |
+                Audio { ref relates_to, .. } | Video { ref relates_to, .. } => relates_to
+        }
+    }
+    fn relates_to_mut(&mut self) -> &mut Option<RelatesTo> {
+        use self::Message::*;
+        match *self {
+            Text { ref mut relates_to, .. } | Notice { ref mut relates_to, .. } | Image { ref mut relates_to, .. } |
+                Emote { ref mut relates_to, .. } | File { ref mut relates_to, .. } | Location { ref mut relates_to, .. } |
+                Audio { ref mut relates_to, .. } | Video { ref mut relates_to, .. } => relates_to
+        }
+    }
+    /// Make this message a reply to another one, adding the quoted fallbacks
+    /// the spec describes for clients that don't understand replies.
+    ///
+    /// - `room_id`, `event_id` and `sender` identify the message being
+    ///   replied to, which is `original`.
+    ///
+    /// Only text, notice and emote messages get fallbacks (and only text and
+    /// notices get an HTML one); other messages just get the `m.in_reply_to`
+    /// relation. Any fallback already in `original` is left out of the quote.
+    pub fn make_reply(&mut self, room_id: &str, event_id: &str, sender: &str, original: &Message) {
+        let mut original = original.clone();
+        original.strip_reply_fallback();
+        let quoted = match original {
+            Message::Text { ref body, .. } | Message::Notice { ref body, .. } => body.clone(),
+            Message::Emote { ref body, .. } => format!("* {}", body),
+            Message::Image { .. } => "sent an image.".into(),
+            Message::File { .. } => "sent a file.".into(),
+            Message::Audio { .. } => "sent an audio file.".into(),
+            Message::Video { .. } => "sent a video.".into(),
+            Message::Location { .. } => "sent a location.".into()
+        };
+        let quoted_html = match original.html_body() {
+            Some(html) => html.to_string(),
+            None => escape_html(&quoted)
+        };
+        let mut fallback = String::new();
+        for (i, line) in quoted.lines().enumerate() {
+            if i == 0 {
+                fallback.push_str(&format!("> <{}> {}\n", sender, line));
+            }
+            else {
+                fallback.push_str(&format!("> {}\n", line));
+            }
+        }
+        let html_fallback = format!(
+            "<mx-reply><blockquote><a href=\"https://matrix.to/#/{}/{}\">In reply to</a> <a href=\"https://matrix.to/#/{}\">{}</a><br />{}</blockquote></mx-reply>",
+            matrix_to_segment(room_id), matrix_to_segment(event_id),
+            matrix_to_segment(sender), escape_html(sender), quoted_html);
+        let html = self.html_body().map(|x| x.to_string())
+            .unwrap_or_else(|| escape_html(self.body()));
+        match *self {
+            Message::Text { ref mut body, ref mut formatted_body, ref mut format, .. } |
+                Message::Notice { ref mut body, ref mut formatted_body, ref mut format, .. } => {
+                *body = format!("{}\n{}", fallback, body);
+                *formatted_body = Some(format!("{}{}", html_fallback, html));
+                *format = Some(HTML_FORMAT.into());
+            },
+            Message::Emote { ref mut body, .. } => {
+                *body = format!("{}\n{}", fallback, body);
+            },
+            _ => {}
+        }
+        self.relates_to_mut()
+            .get_or_insert_with(Default::default)
+            .in_reply_to = Some(InReplyTo { event_id: event_id.into() });
+    }
+    /// If this message is a reply, remove the quoted fallbacks of the message
+    /// it's replying to (see `make_reply`) from its bodies, leaving just the
+    /// reply itself.
+    pub fn strip_reply_fallback(&mut self) {
+        if self.in_reply_to().is_none() {
+            return;
+        }
+        use self::Message::*;
+        match *self {
+            Text { ref mut body, ref mut formatted_body, .. } |
+                Notice { ref mut body, ref mut formatted_body, .. } => {
+                *body = strip_body_fallback(body);
+                if let Some(ref mut fb) = *formatted_body {
+                    *fb = strip_html_fallback(fb);
+                }
+            },
+            Emote { ref mut body, .. } => {
+                *body = strip_body_fallback(body);
+            },
+            _ => {}
+        }
+    }
+}
+/// Remove the leading `> ` lines (and the blank line after them) from a
+/// plain text body.
+fn strip_body_fallback(body: &str) -> String {
+    if !body.starts_with("> ") {
+        return body.into();
+    }
+    let mut lines = body.lines().skip_while(|l| l.starts_with('>')).peekable();
+    if lines.peek() == Some(&"") {
+        lines.next();
+    }
+    lines.collect::<Vec<_>>().join("\n")
+}
+/// Remove the `<mx-reply>` block from an HTML body.
+fn strip_html_fallback(html: &str) -> String {
+    if let Some(start) = html.find("<mx-reply>") {
+        if let Some(end) = html.find("</mx-reply>") {
+            if end > start {
+                return format!("{}{}", &html[..start], &html[end + "</mx-reply>".len()..]);
+            }
+        }
     }
+    html.into()
 }
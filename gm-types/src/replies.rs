@@ -2,6 +2,7 @@
 use crate::room::Room;
 use crate::events::Event;
 use std::collections::HashMap;
+use std::fmt;
 use serde_json::Value;
 use crate::content::room::Member;
 
@@ -38,11 +39,79 @@ pub struct LoginReply {
     /// Will be the same as the corresponding parameter in the request, if one was specified.
     pub device_id: String
 }
+/// An identity provider that can be used to log in with SSO.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IdentityProvider {
+    /// Opaque ID of the provider, for `/login/sso/redirect/{idpId}`.
+    pub id: String,
+    /// Human-readable name of the provider.
+    pub name: String,
+    /// `mxc://` URI of an icon for the provider.
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Brand of the provider (e.g. `github`), for clients that want to style it.
+    #[serde(default)]
+    pub brand: Option<String>
+}
+/// A way of logging in that the homeserver supports.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginFlow {
+    /// The login type (e.g. `m.login.password`, `m.login.sso`).
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// For `m.login.sso`, the identity providers available.
+    #[serde(default)]
+    pub identity_providers: Vec<IdentityProvider>
+}
+/// The reply obtained from `GET /login`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LoginFlowsReply {
+    /// The ways of logging in that the homeserver supports.
+    pub flows: Vec<LoginFlow>
+}
 /// The reply obtained from the `/whoami` API.
 #[derive(Deserialize, Clone, Debug)]
 pub struct WhoamiReply {
     /// The user the access token belongs to.
-    pub user_id: String
+    pub user_id: String,
+    /// The device the access token belongs to, if it belongs to one.
+    #[serde(default)]
+    pub device_id: Option<String>
+}
+/// The reply obtained from `/_matrix/client/versions`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionsReply {
+    /// The versions of the client-server API the server supports (e.g. `r0.6.1`, `v1.1`).
+    pub versions: Vec<String>,
+    /// Experimental features the server supports, and whether they're enabled.
+    #[serde(default)]
+    pub unstable_features: HashMap<String, bool>
+}
+impl VersionsReply {
+    /// Whether the server supports the given spec version.
+    pub fn supports(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| v == version)
+    }
+    /// Whether the server has the given unstable feature enabled.
+    pub fn unstable_feature(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature).cloned().unwrap_or(false)
+    }
+}
+/// Information about a server, from a `.well-known` file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WellKnownServer {
+    /// The base URL of the server.
+    pub base_url: String
+}
+/// The reply obtained from `/.well-known/matrix/client`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WellKnownReply {
+    /// The homeserver clients should use.
+    #[serde(rename = "m.homeserver")]
+    pub homeserver: WellKnownServer,
+    /// The identity server clients should use, if any.
+    #[serde(rename = "m.identity_server", default)]
+    pub identity_server: Option<WellKnownServer>
 }
 /// The reply obtained from getting a room alias.
 #[derive(Deserialize, Clone, Debug)]
@@ -65,6 +134,188 @@ pub struct SetStateReply {
     /// A unique identifier for the event.
     pub event_id: String
 }
+/// An error code, from the `errcode` field of a `BadRequestReply`.
+///
+/// Most of the variants here are the standard error codes from the spec; anything
+/// else ends up in `Unknown`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MatrixErrorCode {
+    /// `M_FORBIDDEN`: forbidden access, e.g. joining a room without permission, failed login.
+    Forbidden,
+    /// `M_UNKNOWN_TOKEN`: the access token specified was not recognised.
+    UnknownToken {
+        /// If true, the client may log in again to get a new access token for
+        /// the same device, without having to wipe its local state.
+        soft_logout: bool
+    },
+    /// `M_MISSING_TOKEN`: no access token was specified for the request.
+    MissingToken,
+    /// `M_USER_LOCKED`: the account has been locked, and cannot be used at this time.
+    UserLocked,
+    /// `M_BAD_JSON`: request contained valid JSON, but it was malformed in some way.
+    BadJson,
+    /// `M_NOT_JSON`: request did not contain valid JSON.
+    NotJson,
+    /// `M_NOT_FOUND`: no resource was found for this request.
+    NotFound,
+    /// `M_LIMIT_EXCEEDED`: too many requests have been sent in a short period of time.
+    LimitExceeded,
+    /// `M_UNRECOGNIZED`: the server did not understand the request.
+    Unrecognized,
+    /// `M_UNKNOWN`: an unknown error has occurred.
+    UnknownError,
+    /// `M_UNAUTHORIZED`: the request was not correctly authorized.
+    Unauthorized,
+    /// `M_USER_DEACTIVATED`: the user ID associated with the request has been deactivated.
+    UserDeactivated,
+    /// `M_USER_IN_USE`: the desired user ID is already taken.
+    UserInUse,
+    /// `M_INVALID_USERNAME`: the desired user ID is not a valid user name.
+    InvalidUsername,
+    /// `M_ROOM_IN_USE`: the room alias given to `/createRoom` is already taken.
+    RoomInUse,
+    /// `M_INVALID_ROOM_STATE`: the initial state given to `/createRoom` is invalid.
+    InvalidRoomState,
+    /// `M_THREEPID_IN_USE`: the given third-party identifier is already in use.
+    ThreepidInUse,
+    /// `M_THREEPID_NOT_FOUND`: the given third-party identifier isn't associated with any user.
+    ThreepidNotFound,
+    /// `M_THREEPID_AUTH_FAILED`: authentication could not be performed on the third-party identifier.
+    ThreepidAuthFailed,
+    /// `M_THREEPID_DENIED`: the server does not permit this third-party identifier.
+    ThreepidDenied,
+    /// `M_THREEPID_MEDIUM_NOT_SUPPORTED`: the homeserver does not support adding a
+    /// third-party identifier of this medium.
+    ThreepidMediumNotSupported,
+    /// `M_SERVER_NOT_TRUSTED`: the client's request used a third-party server the
+    /// homeserver does not trust.
+    ServerNotTrusted,
+    /// `M_UNSUPPORTED_ROOM_VERSION`: the homeserver does not support the requested room version.
+    UnsupportedRoomVersion,
+    /// `M_INCOMPATIBLE_ROOM_VERSION`: the room is of a version the homeserver doesn't support.
+    IncompatibleRoomVersion,
+    /// `M_BAD_STATE`: the requested state change cannot be performed.
+    BadState,
+    /// `M_GUEST_ACCESS_FORBIDDEN`: the room or resource does not permit guests to access it.
+    GuestAccessForbidden,
+    /// `M_CAPTCHA_NEEDED`: a Captcha is required to complete the request.
+    CaptchaNeeded,
+    /// `M_CAPTCHA_INVALID`: the Captcha provided did not match what was expected.
+    CaptchaInvalid,
+    /// `M_MISSING_PARAM`: a required parameter was missing from the request.
+    MissingParam,
+    /// `M_INVALID_PARAM`: a parameter that was specified has the wrong value.
+    InvalidParam,
+    /// `M_TOO_LARGE`: the request or entity was too large.
+    TooLarge,
+    /// `M_EXCLUSIVE`: the resource being requested is reserved by an application service.
+    Exclusive,
+    /// `M_RESOURCE_LIMIT_EXCEEDED`: the homeserver has hit a resource limit (e.g. monthly active users).
+    ResourceLimitExceeded,
+    /// `M_CANNOT_LEAVE_SERVER_NOTICE_ROOM`: the user can't leave the server notices room.
+    CannotLeaveServerNoticeRoom,
+    /// `M_WEAK_PASSWORD`: the password was rejected by the server for being too weak.
+    WeakPassword,
+    /// Some other error code.
+    Unknown(String)
+}
+impl MatrixErrorCode {
+    /// Get the error code string for this error code, e.g. `M_NOT_FOUND`.
+    pub fn as_str(&self) -> &str {
+        use self::MatrixErrorCode::*;
+        match *self {
+            Forbidden => "M_FORBIDDEN",
+            UnknownToken { .. } => "M_UNKNOWN_TOKEN",
+            MissingToken => "M_MISSING_TOKEN",
+            UserLocked => "M_USER_LOCKED",
+            BadJson => "M_BAD_JSON",
+            NotJson => "M_NOT_JSON",
+            NotFound => "M_NOT_FOUND",
+            LimitExceeded => "M_LIMIT_EXCEEDED",
+            Unrecognized => "M_UNRECOGNIZED",
+            UnknownError => "M_UNKNOWN",
+            Unauthorized => "M_UNAUTHORIZED",
+            UserDeactivated => "M_USER_DEACTIVATED",
+            UserInUse => "M_USER_IN_USE",
+            InvalidUsername => "M_INVALID_USERNAME",
+            RoomInUse => "M_ROOM_IN_USE",
+            InvalidRoomState => "M_INVALID_ROOM_STATE",
+            ThreepidInUse => "M_THREEPID_IN_USE",
+            ThreepidNotFound => "M_THREEPID_NOT_FOUND",
+            ThreepidAuthFailed => "M_THREEPID_AUTH_FAILED",
+            ThreepidDenied => "M_THREEPID_DENIED",
+            ThreepidMediumNotSupported => "M_THREEPID_MEDIUM_NOT_SUPPORTED",
+            ServerNotTrusted => "M_SERVER_NOT_TRUSTED",
+            UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
+            IncompatibleRoomVersion => "M_INCOMPATIBLE_ROOM_VERSION",
+            BadState => "M_BAD_STATE",
+            GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
+            CaptchaNeeded => "M_CAPTCHA_NEEDED",
+            CaptchaInvalid => "M_CAPTCHA_INVALID",
+            MissingParam => "M_MISSING_PARAM",
+            InvalidParam => "M_INVALID_PARAM",
+            TooLarge => "M_TOO_LARGE",
+            Exclusive => "M_EXCLUSIVE",
+            ResourceLimitExceeded => "M_RESOURCE_LIMIT_EXCEEDED",
+            CannotLeaveServerNoticeRoom => "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM",
+            WeakPassword => "M_WEAK_PASSWORD",
+            Unknown(ref s) => s
+        }
+    }
+}
+impl<'a> From<&'a str> for MatrixErrorCode {
+    /// Parse an error code string.
+    ///
+    /// `M_UNKNOWN_TOKEN` is parsed with `soft_logout` set to false; use
+    /// `BadRequestReply::error_code` or `UiaaInfo::error_code` to get the real
+    /// value.
+    fn from(code: &'a str) -> Self {
+        use self::MatrixErrorCode::*;
+        match code {
+            "M_FORBIDDEN" => Forbidden,
+            "M_UNKNOWN_TOKEN" => UnknownToken { soft_logout: false },
+            "M_MISSING_TOKEN" => MissingToken,
+            "M_USER_LOCKED" => UserLocked,
+            "M_BAD_JSON" => BadJson,
+            "M_NOT_JSON" => NotJson,
+            "M_NOT_FOUND" => NotFound,
+            "M_LIMIT_EXCEEDED" => LimitExceeded,
+            "M_UNRECOGNIZED" => Unrecognized,
+            "M_UNKNOWN" => UnknownError,
+            "M_UNAUTHORIZED" => Unauthorized,
+            "M_USER_DEACTIVATED" => UserDeactivated,
+            "M_USER_IN_USE" => UserInUse,
+            "M_INVALID_USERNAME" => InvalidUsername,
+            "M_ROOM_IN_USE" => RoomInUse,
+            "M_INVALID_ROOM_STATE" => InvalidRoomState,
+            "M_THREEPID_IN_USE" => ThreepidInUse,
+            "M_THREEPID_NOT_FOUND" => ThreepidNotFound,
+            "M_THREEPID_AUTH_FAILED" => ThreepidAuthFailed,
+            "M_THREEPID_DENIED" => ThreepidDenied,
+            "M_THREEPID_MEDIUM_NOT_SUPPORTED" => ThreepidMediumNotSupported,
+            "M_SERVER_NOT_TRUSTED" => ServerNotTrusted,
+            "M_UNSUPPORTED_ROOM_VERSION" => UnsupportedRoomVersion,
+            "M_INCOMPATIBLE_ROOM_VERSION" => IncompatibleRoomVersion,
+            "M_BAD_STATE" => BadState,
+            "M_GUEST_ACCESS_FORBIDDEN" => GuestAccessForbidden,
+            "M_CAPTCHA_NEEDED" => CaptchaNeeded,
+            "M_CAPTCHA_INVALID" => CaptchaInvalid,
+            "M_MISSING_PARAM" => MissingParam,
+            "M_INVALID_PARAM" => InvalidParam,
+            "M_TOO_LARGE" => TooLarge,
+            "M_EXCLUSIVE" => Exclusive,
+            "M_RESOURCE_LIMIT_EXCEEDED" => ResourceLimitExceeded,
+            "M_CANNOT_LEAVE_SERVER_NOTICE_ROOM" => CannotLeaveServerNoticeRoom,
+            "M_WEAK_PASSWORD" => WeakPassword,
+            x => Unknown(x.into())
+        }
+    }
+}
+impl fmt::Display for MatrixErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 /// A 'standard error response' from a Matrix homeserver.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct BadRequestReply {
@@ -77,7 +328,152 @@ pub struct BadRequestReply {
     pub errcode: String,
     /// Human-readable error message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>
+    pub error: Option<String>,
+    /// For `M_LIMIT_EXCEEDED` errors, the amount of time (in milliseconds) the
+    /// client should wait before trying the request again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_ms: Option<u64>,
+    /// Any other fields sent along with the error.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>
+}
+impl BadRequestReply {
+    /// Get the typed version of this error's `errcode`.
+    pub fn error_code(&self) -> MatrixErrorCode {
+        let soft_logout = self.extra.get("soft_logout")
+            .and_then(|v| v.as_bool());
+        parse_error_code(&self.errcode, soft_logout)
+    }
+}
+/// Parse an error code, along with the error's `soft_logout` field (which
+/// only matters for `M_UNKNOWN_TOKEN`).
+fn parse_error_code(errcode: &str, soft_logout: Option<bool>) -> MatrixErrorCode {
+    match MatrixErrorCode::from(errcode) {
+        MatrixErrorCode::UnknownToken { .. } => {
+            MatrixErrorCode::UnknownToken { soft_logout: soft_logout.unwrap_or(false) }
+        },
+        x => x
+    }
+}
+/// A sequence of User-Interactive Authentication stages that, once all
+/// completed, authenticates a request.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct UiaaFlow {
+    /// The login types of the stages, in the order they must be completed.
+    pub stages: Vec<String>
+}
+/// The reply obtained when a request needs (more) User-Interactive Authentication.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UiaaInfo {
+    /// The ways the request can be authenticated.
+    pub flows: Vec<UiaaFlow>,
+    /// Extra information needed by particular stages (e.g. the recaptcha
+    /// `public_key`), keyed by login type.
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+    /// The session ID, which must be passed back with each stage.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// The stages already completed in this session.
+    #[serde(default)]
+    pub completed: Vec<String>,
+    /// Error code, if the last stage attempted failed.
+    #[serde(default)]
+    pub errcode: Option<String>,
+    /// Human-readable error message, if the last stage attempted failed.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// For `M_UNKNOWN_TOKEN` errors, whether the client should log in again
+    /// for the same device, keeping its local state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_logout: Option<bool>
+}
+impl UiaaInfo {
+    /// Get the stages that could be completed next, in any of the flows that
+    /// are consistent with the stages completed so far.
+    pub fn next_stages(&self) -> Vec<&str> {
+        let mut ret = vec![];
+        for flow in self.flows.iter() {
+            if flow.stages.len() > self.completed.len() && flow.stages.starts_with(&self.completed) {
+                let stage = &flow.stages[self.completed.len()] as &str;
+                if !ret.contains(&stage) {
+                    ret.push(stage);
+                }
+            }
+        }
+        ret
+    }
+    /// Get the typed version of this reply's `errcode`, if it has one.
+    pub fn error_code(&self) -> Option<MatrixErrorCode> {
+        self.errcode.as_ref().map(|c| parse_error_code(c, self.soft_logout))
+    }
+}
+/// The reply obtained from a `requestToken` endpoint, after the homeserver
+/// sends a validation token to an email address or phone number.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestTokenReply {
+    /// The session ID, to pass along with the token (or to an
+    /// `m.login.email.identity` stage) once it's validated.
+    pub sid: String,
+    /// If present, the URL the token should be submitted to, instead of the
+    /// user following the link they were sent.
+    #[serde(default)]
+    pub submit_url: Option<String>
+}
+/// A third-party identifier (e.g. an email address) bound to an account.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThirdPartyIdentifier {
+    /// The medium of the identifier (`email` or `msisdn`).
+    pub medium: String,
+    /// The identifier itself.
+    pub address: String,
+    /// When the identifier was validated, in milliseconds since the epoch.
+    pub validated_at: u64,
+    /// When the identifier was added to the account, in milliseconds since the epoch.
+    pub added_at: u64
+}
+/// The reply obtained from `GET /account/3pid`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThirdPartyIdsReply {
+    /// The third-party identifiers bound to the account.
+    #[serde(default)]
+    pub threepids: Vec<ThirdPartyIdentifier>
+}
+/// The reply obtained from endpoints that may unbind third-party identifiers
+/// from an identity server (e.g. `/account/deactivate`).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnbindReply {
+    /// `success` if the identity server unbound the identifiers, or
+    /// `no-support` if it couldn't (or none was known).
+    pub id_server_unbind_result: String
+}
+/// A device (i.e. a login session) belonging to a user.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    /// The ID of the device.
+    pub device_id: String,
+    /// The display name the user set for the device, if any.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// The IP address the device was last seen at.
+    #[serde(default)]
+    pub last_seen_ip: Option<String>,
+    /// When the device was last seen, in milliseconds since the epoch.
+    #[serde(default)]
+    pub last_seen_ts: Option<u64>
+}
+/// The reply obtained from `GET /devices`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DevicesReply {
+    /// The user's devices.
+    #[serde(default)]
+    pub devices: Vec<Device>
+}
+/// The reply obtained from uploading a filter.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilterReply {
+    /// The ID of the new filter, for use with `/sync`.
+    pub filter_id: String
 }
 /// The reply obtained from `Room::messages`.
 #[derive(Deserialize, Debug)]
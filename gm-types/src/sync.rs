@@ -19,13 +19,30 @@ pub struct Timeline {
     #[serde(default)]
     pub events: Vec<Event>,
     /// A token that can be supplied as the `from` parameter of the
-    /// `/rooms/{roomId}/messages` endpoint.
-    pub prev_batch: String,
+    /// `/rooms/{roomId}/messages` endpoint, to get the events before these.
+    ///
+    /// This is optional because homeservers may leave it out.
+    #[serde(default)]
+    pub prev_batch: Option<String>,
     /// True if the number of events returned was limited by the `limit` on the
     /// filter.
     #[serde(default)]
     pub limited: bool
 }
+/// A summary of a room, for working out its display name.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoomSummary {
+    /// Users (other than the current one) to name the room after, if it
+    /// doesn't have a name or canonical alias. Only sent when it changes.
+    #[serde(rename = "m.heroes", default, skip_serializing_if = "Option::is_none")]
+    pub heroes: Option<Vec<String>>,
+    /// The number of users whose membership is `join`. Only sent when it changes.
+    #[serde(rename = "m.joined_member_count", default, skip_serializing_if = "Option::is_none")]
+    pub joined_member_count: Option<u64>,
+    /// The number of users whose membership is `invite`. Only sent when it changes.
+    #[serde(rename = "m.invited_member_count", default, skip_serializing_if = "Option::is_none")]
+    pub invited_member_count: Option<u64>
+}
 /// Information about a room the user has joined.
 #[derive(Deserialize, Debug)]
 pub struct JoinedRoom {
@@ -36,6 +53,9 @@ pub struct JoinedRoom {
     pub state: Events,
     /// The timeline of messages and state changes in the room.
     pub timeline: Timeline,
+    /// Information about the room that clients may need to display it.
+    #[serde(default)]
+    pub summary: RoomSummary,
     /// The ephemeral events in the room that aren't recorded in the timeline or
     /// state of the room. e.g. typing.
     #[serde(default)]
@@ -55,7 +75,10 @@ pub struct LeftRoom {
     pub state: Events,
     /// The timeline of messages and state changes in the room up to the point
     /// when the user left.
-    pub timeline: Timeline
+    pub timeline: Timeline,
+    /// The private data that this user has attached to this room.
+    #[serde(default)]
+    pub account_data: Events
 }
 /// Information about a room the user has been invited to.
 #[derive(Deserialize, Debug)]
@@ -72,8 +95,16 @@ pub struct InvitedRoom {
     #[serde(default)]
     pub invite_state: Events
 }
-/// The `rooms` component of a `SyncReply`.
+/// Information about a room the user has knocked on.
 #[derive(Deserialize, Debug)]
+pub struct KnockedRoom {
+    /// The stripped state of the room the user has knocked on, like
+    /// `InvitedRoom::invite_state`.
+    #[serde(default)]
+    pub knock_state: Events
+}
+/// The `rooms` component of a `SyncReply`.
+#[derive(Deserialize, Debug, Default)]
 pub struct SyncRooms {
     /// The rooms that the user has joined.
     #[serde(default)]
@@ -83,7 +114,21 @@ pub struct SyncRooms {
     pub invite: HashMap<Room<'static>, InvitedRoom>,
     /// The rooms that the user has left, or been banned from.
     #[serde(default)]
-    pub leave: HashMap<Room<'static>, LeftRoom>
+    pub leave: HashMap<Room<'static>, LeftRoom>,
+    /// The rooms that the user has knocked on.
+    #[serde(default)]
+    pub knock: HashMap<Room<'static>, KnockedRoom>
+}
+/// Which users' devices have changed, for end-to-end encryption.
+#[derive(Deserialize, Debug, Default)]
+pub struct DeviceLists {
+    /// Users whose devices have changed (or who now share an encrypted room
+    /// with us).
+    #[serde(default)]
+    pub changed: Vec<String>,
+    /// Users who no longer share an encrypted room with us.
+    #[serde(default)]
+    pub left: Vec<String>
 }
 /// The reply obtained from `/sync`.
 #[derive(Deserialize, Debug)]
@@ -92,23 +137,145 @@ pub struct SyncReply {
     /// request.
     pub next_batch: String,
     /// Updates to rooms.
+    #[serde(default)]
     pub rooms: SyncRooms,
     /// The global private data created by this user.
     #[serde(default)]
     pub account_data: Events,
     /// The updates to the presence status of other users.
     #[serde(default)]
-    pub presence: Events
+    pub presence: Events,
+    /// Messages sent directly to this device.
+    #[serde(default)]
+    pub to_device: Events,
+    /// Which users' devices have changed, for end-to-end encryption.
+    #[serde(default)]
+    pub device_lists: DeviceLists,
+    /// How many unclaimed one-time keys this device has left, by algorithm.
+    #[serde(default)]
+    pub device_one_time_keys_count: HashMap<String, u64>,
+    /// The algorithms for which this device has an unused fallback key.
+    ///
+    /// This is `None` if the homeserver doesn't support fallback keys.
+    #[serde(default)]
+    pub device_unused_fallback_key_types: Option<Vec<String>>
 }
 impl SyncReply {
-    /// Iterate over events in this reply.
-    pub fn iter_events(&self) -> SyncEventIter {
+    /// Iterate over the timeline events of joined rooms in this reply.
+    ///
+    /// To see everything else as well, use `events`.
+    pub fn iter_events(&self) -> SyncEventIter<'_> {
         let mut rooms = vec![];
         for (id, room) in self.rooms.join.iter() {
             rooms.push((id, room.timeline.events.iter()));
         }
         SyncEventIter { rooms }
     }
+    /// Iterate over every event in this reply, tagged with where it came from.
+    ///
+    /// Global events (account data, presence, and to-device messages) come
+    /// first, followed by the events of each room; for each room, state comes
+    /// before the timeline.
+    pub fn events(&self) -> SyncEvents<'_> {
+        use self::EventSource::*;
+        use self::RoomCategory::*;
+
+        let mut chunks = vec![
+            (AccountData, None, self.account_data.events.iter()),
+            (Presence, None, self.presence.events.iter()),
+            (ToDevice, None, self.to_device.events.iter())
+        ];
+        for (id, room) in self.rooms.join.iter() {
+            chunks.push((State, Some((id, Joined)), room.state.events.iter()));
+            chunks.push((Timeline, Some((id, Joined)), room.timeline.events.iter()));
+            chunks.push((Ephemeral, Some((id, Joined)), room.ephemeral.events.iter()));
+            chunks.push((RoomAccountData, Some((id, Joined)), room.account_data.events.iter()));
+        }
+        for (id, room) in self.rooms.invite.iter() {
+            chunks.push((InviteState, Some((id, Invited)), room.invite_state.events.iter()));
+        }
+        for (id, room) in self.rooms.knock.iter() {
+            chunks.push((KnockState, Some((id, Knocked)), room.knock_state.events.iter()));
+        }
+        for (id, room) in self.rooms.leave.iter() {
+            chunks.push((State, Some((id, Left)), room.state.events.iter()));
+            chunks.push((Timeline, Some((id, Left)), room.timeline.events.iter()));
+            chunks.push((RoomAccountData, Some((id, Left)), room.account_data.events.iter()));
+        }
+        SyncEvents { chunks: chunks.into_iter(), cur: None }
+    }
+}
+/// Where in a `/sync` reply an event came from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EventSource {
+    /// A room's timeline (of messages and state changes).
+    Timeline,
+    /// A room's state, up to the start of the timeline.
+    State,
+    /// A room's ephemeral events (e.g. typing notifications and receipts).
+    Ephemeral,
+    /// The private data the user has attached to a room.
+    RoomAccountData,
+    /// The stripped state of a room the user has been invited to.
+    InviteState,
+    /// The stripped state of a room the user has knocked on.
+    KnockState,
+    /// The user's global private data.
+    AccountData,
+    /// Updates to other users' presence.
+    Presence,
+    /// Messages sent directly to this device.
+    ToDevice
+}
+/// Which part of `SyncRooms` a room was in, i.e. the user's membership of it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RoomCategory {
+    /// The user has joined the room.
+    Joined,
+    /// The user has been invited to the room.
+    Invited,
+    /// The user has left (or been banned from) the room.
+    Left,
+    /// The user has knocked on the room.
+    Knocked
+}
+/// An event from a `/sync` reply, as yielded by `SyncReply::events`.
+#[derive(Copy, Clone, Debug)]
+pub struct SyncEvent<'a> {
+    /// Where the event came from.
+    pub source: EventSource,
+    /// The room the event is in, if it's in one.
+    pub room: Option<&'a Room<'static>>,
+    /// The user's membership of that room, if the event is in one.
+    pub category: Option<RoomCategory>,
+    /// The event itself.
+    pub event: &'a Event
+}
+/// A list of events from one place in a `/sync` reply.
+type EventChunk<'a> = (EventSource, Option<(&'a Room<'static>, RoomCategory)>, slice::Iter<'a, Event>);
+/// Iterator over every event in a `/sync` reply.
+pub struct SyncEvents<'a> {
+    chunks: ::std::vec::IntoIter<EventChunk<'a>>,
+    cur: Option<EventChunk<'a>>
+}
+impl<'a> Iterator for SyncEvents<'a> {
+    type Item = SyncEvent<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((source, room, ref mut iter)) = self.cur {
+                if let Some(event) = iter.next() {
+                    return Some(SyncEvent {
+                        source,
+                        room: room.map(|r| r.0),
+                        category: room.map(|r| r.1),
+                        event
+                    });
+                }
+            }
+            self.cur = Some(self.chunks.next()?);
+        }
+    }
 }
 /// Iterator over events in a `/sync` reply.
 pub struct SyncEventIter<'a> {
@@ -127,6 +294,7 @@ impl<'a> Iterator for SyncEventIter<'a> {
             else {
                 return None;
             }
+            #[allow(unused_must_use)]
             self.rooms.remove(0);
         }
     }